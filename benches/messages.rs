@@ -0,0 +1,50 @@
+use criterion::{black_box, criterion_group, criterion_main, Criterion};
+use std::net::{Ipv4Addr, SocketAddrV4};
+
+use mainline_client::encodings::bytes_from_base32;
+use mainline_client::messages::bencode::{FromBencode, ToBencode};
+use mainline_client::messages::{decode_compact_peers, encode_compact_peers, KRPCMessage};
+
+// Messages as they'd arrive off the wire
+const PING_QUERY: &[u8] = b"d1:ad2:id20:abcdefghij0123456789e1:q4:ping1:t2:aa1:y1:qe";
+const GET_PEERS_RESPONSE: &[u8] = b"d2:ip6:\x7c\x1f\x4b\x15\x1a\xe11:rd2:id20:abcdefghij01234567895:token8:aoeusnth6:valuesl6:axje.u6:idhtnm6:\x7f\x00\x00\x01\x1a\xe1ee1:t2:aa1:y1:re";
+const FIND_NODE_RESPONSE: &[u8] = b"d1:rd2:id20:0123456789abcdefghij5:nodes52:mnopqrstuvwxyz123456axje.uabcdefghij0123456789idhtnme1:t2:aa1:y1:re";
+
+fn from_bencode(c: &mut Criterion) {
+    c.bench_function("from_bencode ping query", |b| {
+        b.iter(|| KRPCMessage::from_bencode(black_box(PING_QUERY)))
+    });
+    c.bench_function("from_bencode get_peers response", |b| {
+        b.iter(|| KRPCMessage::from_bencode(black_box(GET_PEERS_RESPONSE)))
+    });
+    c.bench_function("from_bencode find_node response", |b| {
+        b.iter(|| KRPCMessage::from_bencode(black_box(FIND_NODE_RESPONSE)))
+    });
+}
+
+fn to_bencode(c: &mut Criterion) {
+    let get_peers = KRPCMessage::from_bencode(GET_PEERS_RESPONSE).unwrap();
+    c.bench_function("to_bencode get_peers response", |b| {
+        b.iter(|| black_box(&get_peers).to_bencode())
+    });
+}
+
+fn base32(c: &mut Criterion) {
+    c.bench_function("bytes_from_base32 info hash", |b| {
+        b.iter(|| bytes_from_base32::<20>(black_box("YEX6DQDLXISUVHOJ6UM3GNNKPQJWPKEK")))
+    });
+}
+
+fn compact_peers(c: &mut Criterion) {
+    // A full get_peers response's worth of peers
+    let peers: Vec<SocketAddrV4> = (0..100u8)
+        .map(|i| SocketAddrV4::new(Ipv4Addr::new(10, 0, i, 1), 6881 + i as u16))
+        .collect();
+    let compact = encode_compact_peers(&peers);
+    c.bench_function("decode_compact_peers 100 peers", |b| {
+        b.iter(|| decode_compact_peers(black_box(&compact)))
+    });
+}
+
+criterion_group!(benches, from_bencode, to_bencode, base32, compact_peers);
+criterion_main!(benches);
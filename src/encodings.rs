@@ -32,6 +32,21 @@ pub fn bytes_from_hex<const LEN: usize>(hex: &str) -> Result<[u8; LEN], Encoding
     Ok(bytes)
 }
 
+pub fn bytes_from_hex_vec(hex: &str) -> Result<Vec<u8>, EncodingError> {
+    if !hex.len().is_multiple_of(2) {
+        return Err(InvalidHashLength);
+    }
+
+    hex.as_bytes()
+        .chunks(2)
+        .map(|val| hex_to_byte(val[0], val[1]))
+        .collect()
+}
+
+pub fn hex_from_bytes(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
 #[inline]
 fn base32_decode_char(h: u8) -> Result<u8, EncodingError> {
     // RFC 4648 base 32
@@ -91,6 +106,29 @@ pub fn bytes_from_base32<const LEN: usize>(enc: &str) -> Result<[u8; LEN], Encod
     }
 }
 
+pub fn base32_from_bytes(bytes: &[u8]) -> String {
+    // RFC 4648 base 32, upper case and padded
+    const ALPHABET: &[u8; 32] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZ234567";
+
+    let mut out = String::with_capacity(bytes.len().div_ceil(5) * 8);
+    for chunk in bytes.chunks(5) {
+        let mut padded: [u8; 5] = [0; 5];
+        padded[..chunk.len()].copy_from_slice(chunk);
+        let bits = padded.iter().fold(0u64, |acc, &b| (acc << 8) | b as u64);
+
+        let significant_chars = (chunk.len() * 8).div_ceil(5);
+        for i in 0..8 {
+            if i < significant_chars {
+                let index = (bits >> (35 - i * 5)) & 0x1f;
+                out.push(ALPHABET[index as usize] as char);
+            } else {
+                out.push('=');
+            }
+        }
+    }
+    out
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -110,6 +148,32 @@ mod tests {
         assert_eq!(bad2.err(), Some(InvalidHashLength));
     }
 
+    #[test]
+    fn test_bytes_from_hex_vec() {
+        assert_eq!(bytes_from_hex_vec("abCDef"), Ok(vec![0xAB, 0xCD, 0xEF]));
+        assert_eq!(bytes_from_hex_vec(""), Ok(vec![]));
+        assert_eq!(bytes_from_hex_vec("abc"), Err(InvalidHashLength));
+        assert_eq!(bytes_from_hex_vec("zz"), Err(InvalidHashCharacter));
+    }
+
+    #[test]
+    fn test_hex_from_bytes() {
+        assert_eq!(hex_from_bytes(&[0xAB, 0xCD, 0xEF, 0x01]), "abcdef01");
+        assert_eq!(hex_from_bytes(&[]), "");
+    }
+
+    #[test_case(&[0xFF], "74======"; "1 byte")]
+    #[test_case(&[0x00, 0x45], "ABCQ===="; "2 bytes")]
+    #[test_case(&[0xFF, 0xFF, 0xFF, 0xFF, 0xFF], "77777777"; "Full chunk")]
+    #[test_case(
+        &[0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0x32, 0xf7, 0x21, 0x83, 0xf8, 0xd0],
+        "77777777GL3SDA7Y2A======";
+        "Multiple chunks"
+    )]
+    fn test_base32_from_bytes(bytes: &[u8], expected: &str) {
+        assert_eq!(base32_from_bytes(bytes), expected);
+    }
+
     #[test]
     fn test_bytes_from_base32_case_insensitive() {
         let ac1 = bytes_from_base32::<1>("Ai======");
@@ -1 +1,3 @@
 pub mod encodings;
+pub mod hash;
+pub mod messages;
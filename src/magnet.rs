@@ -1,6 +1,9 @@
-use mainline_client::encodings::{bytes_from_base32, bytes_from_hex, hex_to_byte, EncodingError};
+use mainline_client::encodings::{
+    base32_from_bytes, bytes_from_base32, bytes_from_hex, hex_from_bytes, hex_to_byte,
+    EncodingError,
+};
 
-use std::{borrow::Cow, collections::HashMap, error::Error, fmt, str::FromStr};
+use std::{borrow::Cow, collections::BTreeMap, error::Error, fmt, str::FromStr};
 
 #[derive(Debug, PartialEq, Eq)]
 pub enum MagnetURIError {
@@ -11,6 +14,11 @@ pub enum MagnetURIError {
     UnknownHashFunction,
     InvalidUseOfReservedChar,
     NotImplemented,
+    // Which query parameter `source` came from
+    InvalidParameter {
+        key: String,
+        source: Box<MagnetURIError>,
+    },
 }
 
 impl Error for MagnetURIError {
@@ -24,6 +32,14 @@ impl Error for MagnetURIError {
             UnknownHashFunction => "URN hash function unknown",
             InvalidUseOfReservedChar => "Invalid use of reserved character in query string",
             NotImplemented => "Soz lol",
+            InvalidParameter { .. } => "Invalid magnet parameter",
+        }
+    }
+
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            MagnetURIError::InvalidParameter { source, .. } => Some(source.as_ref()),
+            _ => None,
         }
     }
 }
@@ -43,6 +59,20 @@ impl From<EncodingError> for MagnetURIError {
     }
 }
 
+fn uri_encode_value(value: &str) -> String {
+    let mut ret = String::with_capacity(value.len());
+    for byte in value.bytes() {
+        match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' | b':' => {
+                ret.push(byte as char)
+            }
+            b' ' => ret.push('+'),
+            _ => ret.push_str(&format!("%{:02X}", byte)),
+        }
+    }
+    ret
+}
+
 fn uri_decode_value(value: &str) -> Result<Cow<str>, MagnetURIError> {
     use MagnetURIError::*;
     const INVALID: [char; 3] = ['#', '?', '&'];
@@ -76,7 +106,7 @@ fn uri_decode_value(value: &str) -> Result<Cow<str>, MagnetURIError> {
     }
 }
 
-#[derive(Debug, PartialEq, Eq)]
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
 pub enum MagnetHash {
     SHA1([u8; 20]),
     MD5([u8; 16]),
@@ -93,6 +123,8 @@ impl FromStr for MagnetHash {
         } else if let Some(stripped) = s.strip_prefix("urn:md5:") {
             Ok(MagnetHash::MD5(bytes_from_hex(stripped)?))
         } else if let Some(stripped) = s.strip_prefix("urn:btih:") {
+            // 40 characters is always hex. Base32 info hashes are 32, so a
+            // bad character here is a bad hash, not a mislabelled encoding
             if stripped.len() == 40 {
                 Ok(MagnetHash::BTIH(bytes_from_hex(stripped)?))
             } else {
@@ -104,12 +136,45 @@ impl FromStr for MagnetHash {
     }
 }
 
-#[derive(Debug, PartialEq, Eq)]
+impl fmt::Display for MagnetHash {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            MagnetHash::SHA1(hash) => write!(f, "urn:sha1:{}", base32_from_bytes(hash)),
+            MagnetHash::MD5(hash) => write!(f, "urn:md5:{}", hex_from_bytes(hash)),
+            MagnetHash::BTIH(hash) => write!(f, "urn:btih:{}", hex_from_bytes(hash)),
+            // There is no URN for a missing hash
+            MagnetHash::Invalid => Ok(()),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
 pub struct MagnetFile {
     hash: MagnetHash,
     display_name: String,
 }
 
+impl MagnetFile {
+    /// The BitTorrent info-hash for this file. `None` if the file was given
+    /// some other kind of hash, or no valid hash at all.
+    pub fn btih(&self) -> Option<[u8; 20]> {
+        match self.hash {
+            MagnetHash::BTIH(hash) => Some(hash),
+            _ => None,
+        }
+    }
+
+    /// Display name with surrounding whitespace trimmed and runs of
+    /// whitespace collapsed to a single space. `display_name` keeps the
+    /// value as it was in the link.
+    pub fn normalized_display_name(&self) -> String {
+        self.display_name
+            .split_whitespace()
+            .collect::<Vec<_>>()
+            .join(" ")
+    }
+}
+
 impl Default for MagnetFile {
     fn default() -> Self {
         MagnetFile {
@@ -122,6 +187,87 @@ impl Default for MagnetFile {
 #[derive(Debug, PartialEq, Eq)]
 pub struct MagnetFiles {
     files: Vec<MagnetFile>,
+    // Tracker URLs from `tr`. These apply to the whole link, not one file
+    trackers: Vec<String>,
+}
+
+impl MagnetFiles {
+    /// Puts the files and trackers in a canonical order so that equivalent
+    /// magnets produce the same string when displayed.
+    pub fn normalize(&self) -> MagnetFiles {
+        let mut files: Vec<MagnetFile> = self
+            .files
+            .iter()
+            .map(|file| MagnetFile {
+                hash: file.hash.clone(),
+                display_name: file.normalized_display_name(),
+            })
+            .collect();
+        files.sort();
+        files.dedup();
+        let mut trackers = self.trackers.clone();
+        trackers.sort();
+        trackers.dedup();
+        MagnetFiles { files, trackers }
+    }
+
+    /// The first file's info-hash as lowercase hex, for showing to people.
+    /// `None` if the first file doesn't have a BTIH.
+    pub fn primary_hash_hex(&self) -> Option<String> {
+        let hash = self.files.first()?.btih()?;
+        Some(hex_from_bytes(&hash))
+    }
+}
+
+impl fmt::Display for MagnetFiles {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        // A lone file doesn't need an index
+        let suffix = |i: usize| {
+            if self.files.len() == 1 {
+                "".to_string()
+            } else {
+                format!(".{}", i + 1)
+            }
+        };
+
+        let mut params = Vec::new();
+        for (i, file) in self.files.iter().enumerate() {
+            if !file.display_name.is_empty() {
+                params.push(format!(
+                    "dn{}={}",
+                    suffix(i),
+                    uri_encode_value(&file.display_name)
+                ));
+            }
+            if file.hash != MagnetHash::Invalid {
+                params.push(format!("xt{}={}", suffix(i), file.hash));
+            }
+        }
+        for tracker in &self.trackers {
+            params.push(format!("tr={}", uri_encode_value(tracker)));
+        }
+        params.sort();
+        write!(f, "magnet:?{}", params.join("&"))
+    }
+}
+
+// `;` is a legacy separator from HTML forms, still seen in the wild. It also
+// turns up unescaped in values (`dn=Tom;Jerry`), so only split on it when what
+// follows looks like another parameter.
+fn split_params(query: &str) -> impl Iterator<Item = &str> {
+    query.split('&').flat_map(|segment| {
+        let mut params = Vec::new();
+        let mut start = 0;
+        for (i, _) in segment.match_indices(';') {
+            let next = segment[i + 1..].split(';').next().unwrap_or("");
+            if next.contains('=') {
+                params.push(&segment[start..i]);
+                start = i + 1;
+            }
+        }
+        params.push(&segment[start..]);
+        params
+    })
 }
 
 impl FromStr for MagnetFiles {
@@ -130,25 +276,41 @@ impl FromStr for MagnetFiles {
     fn from_str(s: &str) -> Result<MagnetFiles, Self::Err> {
         use MagnetURIError::*;
 
+        // Fragments aren't part of the query, drop them
+        let s = s.split_once('#').map_or(s, |(uri, _)| uri);
         if let Some(data) = s.strip_prefix("magnet:?") {
-            let mut files: HashMap<&str, MagnetFile> = HashMap::new();
-            for serialised_pair in data.split('&') {
+            // Files are keyed on their parameter index, so `xt.1` pairs with `dn.1`.
+            // Bare `xt`/`dn` parameters get their own empty index rather than
+            // sharing "1", otherwise they would clobber an explicit `xt.1`.
+            let mut files: BTreeMap<&str, MagnetFile> = BTreeMap::new();
+            let mut trackers = Vec::new();
+            for serialised_pair in split_params(data) {
+                if serialised_pair.is_empty() {
+                    continue;
+                }
+                // A key with no value (`&x&`) doesn't tell us anything, skip it
                 if let Some((key, encoded_value)) = serialised_pair.split_once('=') {
-                    let value = uri_decode_value(encoded_value)?;
+                    let invalid = |source: MagnetURIError| InvalidParameter {
+                        key: key.to_string(),
+                        source: Box::new(source),
+                    };
+                    let value = uri_decode_value(encoded_value).map_err(invalid)?;
                     if key.starts_with("xt") {
-                        let file_key = key.strip_prefix("xt.").unwrap_or("1");
-                        files.entry(file_key).or_default().hash = MagnetHash::from_str(&value)?;
+                        let file_key = key.strip_prefix("xt.").unwrap_or("");
+                        files.entry(file_key).or_default().hash =
+                            MagnetHash::from_str(&value).map_err(invalid)?;
                     } else if key.starts_with("dn") {
-                        let file_key = key.strip_prefix("dn.").unwrap_or("1");
+                        let file_key = key.strip_prefix("dn.").unwrap_or("");
                         files.entry(file_key).or_default().display_name = (*value).to_string();
+                    } else if key == "tr" || key.starts_with("tr.") {
+                        trackers.push((*value).to_string());
                     }
-                } else {
-                    todo!("need to log a warning here")
-                };
+                }
             }
 
             Ok(MagnetFiles {
                 files: files.into_iter().map(|kv_pair| kv_pair.1).collect(),
+                trackers,
             })
         } else if !s.starts_with("magnet:") {
             Err(InvalidURIScheme)
@@ -158,6 +320,25 @@ impl FromStr for MagnetFiles {
     }
 }
 
+/// Takes an info-hash the way a user is likely to paste it: 40 hex characters,
+/// 32 base32 characters, or a whole magnet link containing a btih.
+pub fn parse_info_hash(input: &str) -> Result<[u8; 20], MagnetURIError> {
+    let input = input.trim();
+    if input.starts_with("magnet:") {
+        MagnetFiles::from_str(input)?
+            .files
+            .iter()
+            .find_map(MagnetFile::btih)
+            .ok_or(MagnetURIError::UnknownHashFunction)
+    } else {
+        match input.len() {
+            40 => Ok(bytes_from_hex(input)?),
+            32 => Ok(bytes_from_base32(input)?),
+            _ => Err(MagnetURIError::InvalidHashLength),
+        }
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -183,6 +364,41 @@ mod tests {
         assert_eq!(MagnetHash::from_str(s), Ok(expected));
     }
 
+    #[test_case(
+        "urn:btih:209c8226b299b308beaf2b9cd3fb49212dbd13eg",
+        InvalidHashCharacter;
+        "BTIH 40 chars not hex"
+    )]
+    #[test_case(
+        "urn:btih:209c8226b299b308beaf2b9cd3fb49212dbd13",
+        InvalidHashLength;
+        "BTIH wrong length"
+    )]
+    #[test_case("urn:sha256:abcd", UnknownHashFunction; "Unknown hash")]
+    fn hash_from_str_invalid(s: &str, expected: MagnetURIError) {
+        assert_eq!(MagnetHash::from_str(s), Err(expected));
+    }
+
+    #[test_case(
+        MagnetHash::MD5([193, 47, 225, 192, 107, 186, 37, 74, 157, 201, 245, 25, 179, 53, 170, 124]),
+        "urn:md5:c12fe1c06bba254a9dc9f519b335aa7c";
+        "MD5"
+    )]
+    #[test_case(
+        MagnetHash::SHA1([193, 47, 225, 192, 107, 186, 37, 74, 157, 201, 245, 25, 179, 53, 170, 124,  19, 103, 168, 138]),
+        "urn:sha1:YEX6DQDLXISUVHOJ6UM3GNNKPQJWPKEK";
+        "SHA1"
+    )]
+    #[test_case(
+        MagnetHash::BTIH([32, 156, 130, 38, 178, 153, 179, 8, 190, 175, 43, 156, 211, 251, 73, 33, 45, 189, 19, 236]),
+        "urn:btih:209c8226b299b308beaf2b9cd3fb49212dbd13ec";
+        "BTIH"
+    )]
+    fn hash_to_string(hash: MagnetHash, expected: &str) {
+        assert_eq!(hash.to_string(), expected);
+        assert_eq!(MagnetHash::from_str(expected), Ok(hash));
+    }
+
     #[test_case(
         "xt.abc=urn:md5:c12fe1c06bba254a9dc9f519b335aa7c",
         MagnetFile {
@@ -223,6 +439,14 @@ mod tests {
         }
         ; "BITH hex Decode"
     )]
+    #[test_case(
+        "xt=urn:md5:c12fe1c06bba254a9dc9f519b335aa7c;dn=example",
+        MagnetFile {
+            hash: MagnetHash::MD5([193, 47, 225, 192, 107, 186, 37, 74, 157, 201, 245, 25, 179, 53, 170, 124]),
+            display_name: "example".to_owned()
+        }
+        ; "Semicolon separated"
+    )]
     fn file_from_str(magnet_params: &str, expected: MagnetFile) {
         let magnet = MagnetFiles::from_str(&format!("magnet:?{}", magnet_params));
         assert!(magnet.is_ok());
@@ -231,6 +455,294 @@ mod tests {
         assert_eq!(magnet, Some(&expected));
     }
 
+    #[test]
+    fn indexed_files_from_str() {
+        let magnet = MagnetFiles::from_str(
+            "magnet:?xt.1=urn:md5:c12fe1c06bba254a9dc9f519b335aa7c&dn.2=second\
+             &xt.2=urn:btih:c12fe1c06bba254a9dc9f519b335aa7c1367a88a&dn.1=first",
+        );
+        assert_eq!(
+            magnet,
+            Ok(MagnetFiles {
+                files: vec![
+                    MagnetFile {
+                        hash: MagnetHash::MD5([
+                            193, 47, 225, 192, 107, 186, 37, 74, 157, 201, 245, 25, 179, 53, 170,
+                            124
+                        ]),
+                        display_name: "first".to_owned()
+                    },
+                    MagnetFile {
+                        hash: MagnetHash::BTIH([
+                            193, 47, 225, 192, 107, 186, 37, 74, 157, 201, 245, 25, 179, 53, 170,
+                            124, 19, 103, 168, 138
+                        ]),
+                        display_name: "second".to_owned()
+                    },
+                ],
+                trackers: vec![],
+            })
+        );
+    }
+
+    #[test]
+    fn bare_and_indexed_files_dont_collide() {
+        let magnet = MagnetFiles::from_str(
+            "magnet:?xt=urn:md5:c12fe1c06bba254a9dc9f519b335aa7c&dn=bare\
+             &xt.1=urn:btih:c12fe1c06bba254a9dc9f519b335aa7c1367a88a&dn.1=indexed",
+        )
+        .unwrap();
+        assert_eq!(magnet.files.len(), 2);
+        assert_eq!(magnet.files[0].display_name, "bare");
+        assert!(matches!(magnet.files[0].hash, MagnetHash::MD5(_)));
+        assert_eq!(magnet.files[1].display_name, "indexed");
+        assert!(matches!(magnet.files[1].hash, MagnetHash::BTIH(_)));
+    }
+
+    #[test]
+    fn empty_query_and_fragment() {
+        assert_eq!(
+            MagnetFiles::from_str("magnet:?"),
+            Ok(MagnetFiles {
+                files: vec![],
+                trackers: vec![]
+            })
+        );
+        assert_eq!(
+            MagnetFiles::from_str("magnet:?#frag"),
+            Ok(MagnetFiles {
+                files: vec![],
+                trackers: vec![]
+            })
+        );
+
+        let with_fragment = MagnetFiles::from_str(
+            "magnet:?xt=urn:md5:c12fe1c06bba254a9dc9f519b335aa7c&dn=name#frag",
+        );
+        let without_fragment =
+            MagnetFiles::from_str("magnet:?xt=urn:md5:c12fe1c06bba254a9dc9f519b335aa7c&dn=name");
+        assert!(with_fragment.is_ok());
+        assert_eq!(with_fragment, without_fragment);
+    }
+
+    #[test_case("magnet:?dn=Tom;Jerry", "Tom;Jerry"; "In value")]
+    #[test_case("magnet:?dn=Tom;Jerry;", "Tom;Jerry;"; "Trailing")]
+    #[test_case("magnet:?xt=urn:md5:c12fe1c06bba254a9dc9f519b335aa7c;dn=Tom;Jerry", "Tom;Jerry"; "After separator")]
+    #[test_case("magnet:?dn=Tom;Jerry&xt=urn:md5:c12fe1c06bba254a9dc9f519b335aa7c", "Tom;Jerry"; "Before ampersand")]
+    fn semicolon_in_value(uri: &str, expected: &str) {
+        let magnet = MagnetFiles::from_str(uri).unwrap();
+        assert_eq!(magnet.files.len(), 1);
+        assert_eq!(magnet.files[0].display_name, expected);
+    }
+
+    #[test]
+    fn semicolon_separator() {
+        assert_eq!(
+            MagnetFiles::from_str("magnet:?xt=urn:md5:c12fe1c06bba254a9dc9f519b335aa7c;dn=name;x"),
+            MagnetFiles::from_str("magnet:?xt=urn:md5:c12fe1c06bba254a9dc9f519b335aa7c&dn=name;x")
+        );
+        let magnet =
+            MagnetFiles::from_str("magnet:?x;xt=urn:md5:c12fe1c06bba254a9dc9f519b335aa7c;dn=name")
+                .unwrap();
+        assert_eq!(magnet.files[0].display_name, "name");
+        assert!(matches!(magnet.files[0].hash, MagnetHash::MD5(_)));
+    }
+
+    #[test_case("magnet:?x&xt=urn:md5:c12fe1c06bba254a9dc9f519b335aa7c&dn=name"; "Leading")]
+    #[test_case("magnet:?xt=urn:md5:c12fe1c06bba254a9dc9f519b335aa7c&dn&dn=name"; "Middle")]
+    #[test_case("magnet:?xt=urn:md5:c12fe1c06bba254a9dc9f519b335aa7c&dn=name&x"; "Trailing")]
+    fn valueless_parameters_skipped(uri: &str) {
+        assert_eq!(
+            MagnetFiles::from_str(uri),
+            MagnetFiles::from_str("magnet:?xt=urn:md5:c12fe1c06bba254a9dc9f519b335aa7c&dn=name")
+        );
+        assert!(MagnetFiles::from_str(uri).is_ok());
+    }
+
+    #[test]
+    fn invalid_parameter_has_key() {
+        let err = MagnetFiles::from_str("magnet:?dn=fine&xt.2=urn:btih:c12fe1c06bba").unwrap_err();
+        assert_eq!(
+            err,
+            InvalidParameter {
+                key: "xt.2".to_string(),
+                source: Box::new(InvalidHashLength)
+            }
+        );
+        assert_eq!(
+            err.source().map(|source| source.to_string()),
+            Some("InvalidHashLength".to_string())
+        );
+
+        let err = MagnetFiles::from_str("magnet:?dn=%zz").unwrap_err();
+        assert!(matches!(err, InvalidParameter { key, .. } if key == "dn"));
+    }
+
+    #[test]
+    fn percent_encoding_case() {
+        let upper = MagnetFiles::from_str(
+            "magnet:?xt=urn%3Amd5%3Ac12fe1c06bba254a9dc9f519b335aa7c&dn=a%3Ab",
+        );
+        let lower = MagnetFiles::from_str(
+            "magnet:?xt=urn%3amd5%3ac12fe1c06bba254a9dc9f519b335aa7c&dn=a%3ab",
+        );
+        assert!(upper.is_ok());
+        assert_eq!(upper, lower);
+        assert_eq!(upper.unwrap().files[0].display_name, "a:b");
+    }
+
+    #[test_case("magnet:?xt=urn:btih:C12FE1C06BBA254A9DC9F519B335AA7C1367A88A&dn=a", Some("c12fe1c06bba254a9dc9f519b335aa7c1367a88a"); "Hex BTIH")]
+    #[test_case("magnet:?xt=urn:btih:YEX6DQDLXISUVHOJ6UM3GNNKPQJWPKEK", Some("c12fe1c06bba254a9dc9f519b335aa7c1367a88a"); "Base32 BTIH")]
+    #[test_case("magnet:?xt=urn:md5:c12fe1c06bba254a9dc9f519b335aa7c", None; "Not a BTIH")]
+    #[test_case("magnet:?", None; "No files")]
+    fn primary_hash_hex(uri: &str, expected: Option<&str>) {
+        let magnet = MagnetFiles::from_str(uri).unwrap();
+        assert_eq!(magnet.primary_hash_hex().as_deref(), expected);
+    }
+
+    #[test]
+    fn normalize() {
+        let spaced = MagnetFiles::from_str("magnet:?dn=+My+++File++Name+").unwrap();
+        assert_eq!(spaced.files[0].display_name, " My   File  Name ");
+        assert_eq!(spaced.files[0].normalized_display_name(), "My File Name");
+        assert_eq!(spaced.normalize().to_string(), "magnet:?dn=My+File+Name");
+
+        let magnet1 = MagnetFiles::from_str(
+            "magnet:?dn=My+File%21&xt=urn:btih:C12FE1C06BBA254A9DC9F519B335AA7C1367A88A",
+        )
+        .unwrap();
+        let magnet2 = MagnetFiles::from_str(
+            "magnet:?xt=urn:btih:c12fe1c06bba254a9dc9f519b335aa7c1367a88a&dn=My%20File!",
+        )
+        .unwrap();
+        assert_eq!(
+            magnet1.normalize().to_string(),
+            "magnet:?dn=My+File%21&xt=urn:btih:c12fe1c06bba254a9dc9f519b335aa7c1367a88a"
+        );
+        assert_eq!(
+            magnet1.normalize().to_string(),
+            magnet2.normalize().to_string()
+        );
+
+        let multi1 = MagnetFiles::from_str(
+            "magnet:?xt.1=urn:md5:c12fe1c06bba254a9dc9f519b335aa7c&dn.1=a\
+             &xt.2=urn:btih:c12fe1c06bba254a9dc9f519b335aa7c1367a88a&dn.2=b",
+        )
+        .unwrap();
+        let multi2 = MagnetFiles::from_str(
+            "magnet:?dn.1=b&xt.1=urn:btih:C12FE1C06BBA254A9DC9F519B335AA7C1367A88A\
+             &dn.2=a&xt.2=urn:md5:C12FE1C06BBA254A9DC9F519B335AA7C",
+        )
+        .unwrap();
+        assert_eq!(
+            multi1.normalize().to_string(),
+            multi2.normalize().to_string()
+        );
+        assert_eq!(
+            MagnetFiles::from_str(&multi1.normalize().to_string())
+                .unwrap()
+                .normalize(),
+            multi1.normalize()
+        );
+    }
+
+    #[test]
+    fn normalize_keeps_trackers() {
+        let magnet = MagnetFiles::from_str(
+            "magnet:?xt=urn:btih:c12fe1c06bba254a9dc9f519b335aa7c1367a88a\
+             &tr=udp%3A%2F%2Ftracker.b.example%3A80&tr=http%3A%2F%2Ftracker.a.example%2Fannounce\
+             &tr=udp%3A%2F%2Ftracker.b.example%3A80",
+        )
+        .unwrap();
+        assert_eq!(
+            magnet.trackers,
+            vec![
+                "udp://tracker.b.example:80",
+                "http://tracker.a.example/announce",
+                "udp://tracker.b.example:80"
+            ]
+        );
+
+        let normalized = magnet.normalize();
+        assert_eq!(
+            normalized.trackers,
+            vec![
+                "http://tracker.a.example/announce",
+                "udp://tracker.b.example:80"
+            ]
+        );
+        assert_eq!(
+            normalized.to_string(),
+            "magnet:?tr=http:%2F%2Ftracker.a.example%2Fannounce&tr=udp:%2F%2Ftracker.b.example:80\
+             &xt=urn:btih:c12fe1c06bba254a9dc9f519b335aa7c1367a88a"
+        );
+        // Nothing lost going round again
+        assert_eq!(
+            MagnetFiles::from_str(&normalized.to_string())
+                .unwrap()
+                .normalize(),
+            normalized
+        );
+
+        // Order and duplicates in the link don't matter
+        let reordered = MagnetFiles::from_str(
+            "magnet:?tr=http%3A%2F%2Ftracker.a.example%2Fannounce&tr=udp%3A%2F%2Ftracker.b.example%3A80\
+             &xt=urn:btih:C12FE1C06BBA254A9DC9F519B335AA7C1367A88A",
+        )
+        .unwrap();
+        assert_eq!(reordered.normalize(), normalized);
+    }
+
+    #[test_case("urn:btih:c12fe1c06bba254a9dc9f519b335aa7c1367a8", InvalidHashLength; "Wrong length")]
+    #[test_case("urn:btih:c12fe1c06bba254a9dc9f519b335aa7c1367a88g", InvalidHashCharacter; "Not hex")]
+    #[test_case("urn:btih:YEX6DQDLXISUVHOJ6UM3GNNKPQJWPKE8", InvalidHashCharacter; "Not base32")]
+    #[test_case("urn:btih:YEX6DQDLXISUVHOJ6UM3GNNKPQJWPKEKA", InvalidHashLength; "Base32 wrong length")]
+    fn malformed_btih_refused(xt: &str, expected: MagnetURIError) {
+        // A bad hash fails the whole link, rather than leaving a file whose
+        // btih() quietly returns None
+        assert_eq!(
+            MagnetFiles::from_str(&format!("magnet:?xt={}&dn=name", xt)),
+            Err(InvalidParameter {
+                key: "xt".to_string(),
+                source: Box::new(expected)
+            })
+        );
+    }
+
+    #[test]
+    fn file_btih() {
+        let magnet = MagnetFiles::from_str(
+            "magnet:?xt=urn:btih:c12fe1c06bba254a9dc9f519b335aa7c1367a88a&dn.1=missing",
+        )
+        .unwrap();
+        assert_eq!(
+            magnet.files[0].btih(),
+            Some([
+                193, 47, 225, 192, 107, 186, 37, 74, 157, 201, 245, 25, 179, 53, 170, 124, 19, 103,
+                168, 138
+            ])
+        );
+        // dn.1 has no matching xt.1 so never got a hash
+        assert_eq!(magnet.files[1].hash, MagnetHash::Invalid);
+        assert_eq!(magnet.files[1].btih(), None);
+
+        let md5 = MagnetFile {
+            hash: MagnetHash::MD5([0; 16]),
+            display_name: "".to_owned(),
+        };
+        assert_eq!(md5.btih(), None);
+    }
+
+    #[test]
+    fn display_without_hash() {
+        // Writing `xt=` with nothing after it wouldn't parse back
+        let magnet = MagnetFiles::from_str("magnet:?dn=name").unwrap();
+        assert_eq!(magnet.files[0].hash, MagnetHash::Invalid);
+        let displayed = magnet.to_string();
+        assert_eq!(displayed, "magnet:?dn=name");
+        assert_eq!(MagnetFiles::from_str(&displayed), Ok(magnet));
+    }
+
     #[test]
     fn test_uri_decode_value() {
         let no_replace_needed = uri_decode_value("ABCD").unwrap();
@@ -249,4 +761,56 @@ mod tests {
     fn test_uri_decode_value_invalid(s: &str) {
         assert_eq!(uri_decode_value(s), Err(InvalidUseOfReservedChar));
     }
+
+    #[test_case("c12fe1c06bba254a9dc9f519b335aa7c1367a88a"; "Hex")]
+    #[test_case("YEX6DQDLXISUVHOJ6UM3GNNKPQJWPKEK"; "Base32")]
+    #[test_case(" C12FE1C06BBA254A9DC9F519B335AA7C1367A88A\n"; "Padded upper case hex")]
+    #[test_case(
+        "magnet:?dn=example&xt=urn:btih:YEX6DQDLXISUVHOJ6UM3GNNKPQJWPKEK";
+        "Magnet"
+    )]
+    #[test_case(
+        "magnet:?xt=urn:btih:c12fe1c06bba254a9dc9f519b335aa7c1367a88a&x";
+        "Magnet with valueless parameter"
+    )]
+    fn info_hash_from_input(input: &str) {
+        assert_eq!(
+            parse_info_hash(input),
+            Ok([
+                193, 47, 225, 192, 107, 186, 37, 74, 157, 201, 245, 25, 179, 53, 170, 124, 19, 103,
+                168, 138
+            ])
+        );
+    }
+
+    #[test_case("c12fe1c06bba", MagnetURIError::InvalidHashLength; "Too short")]
+    #[test_case(
+        "z12fe1c06bba254a9dc9f519b335aa7c1367a88a",
+        MagnetURIError::InvalidHashCharacter;
+        "Not hex"
+    )]
+    #[test_case(
+        "magnet:?xt=urn:md5:c12fe1c06bba254a9dc9f519b335aa7c",
+        MagnetURIError::UnknownHashFunction;
+        "Magnet without btih"
+    )]
+    fn invalid_info_hash_input(input: &str, expected: MagnetURIError) {
+        assert_eq!(parse_info_hash(input), Err(expected));
+    }
+
+    // Whatever gets pasted in, it's an error rather than a panic
+    #[test_case(""; "Empty")]
+    #[test_case("magnet:"; "No query")]
+    #[test_case("magnet:?"; "Empty query")]
+    #[test_case("magnet:?x"; "Only a valueless parameter")]
+    #[test_case("magnet:?xt"; "Valueless xt")]
+    #[test_case("magnet:?xt="; "Empty xt")]
+    #[test_case("magnet:?xt=urn:btih:"; "Empty btih")]
+    #[test_case("magnet:?xt=urn:btih:c12fe1c06bba254a9dc9f519b335aa7c1367a8%"; "Truncated escape")]
+    #[test_case("magnet:?dn=%zz&xt=urn:btih:c12fe1c06bba254a9dc9f519b335aa7c1367a88a"; "Bad escape")]
+    #[test_case("éééééééééééééééééééé"; "Multibyte hex length")]
+    #[test_case("éééééééééééééééé"; "Multibyte base32 length")]
+    fn malformed_info_hash_input(input: &str) {
+        assert!(parse_info_hash(input).is_err());
+    }
 }
@@ -1,13 +1,33 @@
 #![feature(cow_is_borrowed)]
 
 mod magnet;
-mod messages;
+mod transactions;
+mod transport;
 
+use mainline_client::messages;
 use messages::bencode::{FromBencode, ToBencode};
 use messages::*;
+use transactions::{PendingQuery, TransactionTable};
+use transport::Transport;
 
-use std::net::{Ipv4Addr, SocketAddrV4, UdpSocket};
-use std::time::Duration;
+use std::io;
+use std::net::{Ipv4Addr, SocketAddr, SocketAddrV4, UdpSocket};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
+
+const BOOTSTRAP_NODE: SocketAddr =
+    SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::new(127, 0, 0, 1), 6881));
+
+// How long to wait for an answer, however much other traffic turns up
+const RESPONSE_TIMEOUT: Duration = Duration::from_secs(10);
+
+// Largest UDP payload. Responses with values or nodes6 easily go over 512
+// bytes, and anything bigger than the buffer gets truncated
+const MAX_DATAGRAM_SIZE: usize = 65507;
+
+// Protocol errors mean a node couldn't understand something we sent, so they
+// are worth keeping track of separately from other failures
+static PROTOCOL_ERRORS: AtomicUsize = AtomicUsize::new(0);
 
 fn grab_socket() -> Result<UdpSocket, std::io::Error> {
     let localhost = Ipv4Addr::new(0, 0, 0, 0);
@@ -24,14 +44,43 @@ fn rand_buff<const N: usize>() -> [u8; N] {
 fn ip_from_ping<'a>(msg: &'a KRPCMessage) -> Option<&'a [u8; 4]> {
     if let KRPCMessageDetails::Response(response) = &msg.message &&
         let KRPCResponse::Ping { ip: opt_ip, .. } = response &&
-        let Some(ip) = opt_ip {
-        let messages::Ip::V4 { addr, ..} = ip;
+        let Some(messages::Ip::V4 { addr, .. }) = opt_ip {
         Some(addr)
     } else {
         None
     }
 }
 
+fn respond_to_ping<'a>(
+    query: &KRPCMessage<'a>,
+    id: &'a [u8; 20],
+    source: Ip,
+) -> Option<KRPCMessage<'a>> {
+    // Echo the querier's address back to them as described in BEP 42
+    if let KRPCMessageDetails::Query(KRPCQuery::Ping { .. }) = query.message {
+        Some(KRPCMessage::response(
+            query,
+            KRPCResponse::Ping {
+                ip: Some(source),
+                id,
+            },
+        ))
+    } else {
+        None
+    }
+}
+
+fn respond_to_query<'a>(query: &KRPCMessage<'a>, id: &'a [u8; 20], source: Ip) -> KRPCMessage<'a> {
+    // Only pings are served so far. BEP 5 says to reject the rest rather
+    // than leave the querier waiting
+    respond_to_ping(query, id, source).unwrap_or_else(|| {
+        KRPCMessage::error(
+            query,
+            KRPCError::MethodUnknown("Method Unknown".to_string()),
+        )
+    })
+}
+
 fn node_id(ip: &[u8; 4]) -> [u8; 20] {
     // Calculate proper node ID as specified in http://www.bittorrent.org/beps/bep_0042.html
     let mut out = rand_buff::<20>();
@@ -50,24 +99,91 @@ fn node_id(ip: &[u8; 4]) -> [u8; 20] {
     out
 }
 
-fn bootstrap(socket: &UdpSocket) -> Result<(), Box<dyn std::error::Error>> {
-    let mut buf = [0; 512];
-    let mut transaction_id = rand_buff::<2>();
+fn error_from_response(message: &KRPCMessage, from: SocketAddr) -> Result<(), KRPCError> {
+    match &message.message {
+        KRPCMessageDetails::Error(KRPCError::ProtocolError(msg)) => {
+            PROTOCOL_ERRORS.fetch_add(1, Ordering::Relaxed);
+            eprintln!(
+                "Protocol error from {}, our query was probably badly encoded: {}",
+                from, msg
+            );
+            Err(KRPCError::ProtocolError(msg.clone()))
+        }
+        KRPCMessageDetails::Error(err) => Err(err.clone()),
+        _ => Ok(()),
+    }
+}
+
+/// Receives until `addr` answers one of the queries in `transactions`,
+/// answering queries from other nodes as they turn up. The socket is shared
+/// so anything could arrive in between. Responses we aren't waiting on and
+/// datagrams that don't decode are dropped, and after `timeout` we give up
+/// however busy the socket is.
+fn recv_response<'b, T: Transport>(
+    socket: &T,
+    transactions: &mut TransactionTable,
+    id: &[u8; 20],
+    addr: SocketAddr,
+    buf: &'b mut [u8],
+    timeout: Duration,
+) -> Result<KRPCMessage<'b>, Box<dyn std::error::Error>> {
+    let deadline = Instant::now() + timeout;
+    let number_of_bytes = loop {
+        // Otherwise one blocking receive could run on well past the deadline
+        let remaining = deadline
+            .checked_duration_since(Instant::now())
+            .filter(|remaining| !remaining.is_zero())
+            .ok_or_else(|| io::Error::from(io::ErrorKind::TimedOut))?;
+        socket.set_read_timeout(Some(remaining))?;
+        let (number_of_bytes, from) = socket.recv_from(buf)?;
+        let message = match KRPCMessage::from_bencode(&buf[..number_of_bytes]) {
+            Ok(message) => message,
+            Err(err) => {
+                eprintln!("Couldn't decode message from {}: {}", from, err);
+                continue;
+            }
+        };
+        match &message.message {
+            KRPCMessageDetails::Query(_) => {
+                let response = respond_to_query(&message, id, Ip::from(from));
+                // Not being able to answer shouldn't stop our own lookup
+                if let Err(err) = socket.send_to(&response.to_bencode(), from) {
+                    eprintln!("Failed to answer {}: {}", from, err);
+                }
+            }
+            // Checked first so a forged answer doesn't use up the transaction
+            _ if from != addr => (),
+            _ => {
+                if transactions.match_response(&message).is_some() {
+                    break number_of_bytes;
+                }
+            }
+        }
+    };
+    // The borrow checker won't let the message out of the loop, so decode it
+    // again here
+    Ok(KRPCMessage::from_bencode(&buf[..number_of_bytes])?)
+}
+
+fn bootstrap<T: Transport>(socket: &T) -> Result<(), Box<dyn std::error::Error>> {
+    let mut buf = vec![0; MAX_DATAGRAM_SIZE];
     let mut message_id = rand_buff::<20>();
+    let mut transactions = TransactionTable::default();
 
-    getrandom::getrandom(&mut transaction_id).map_err(|_| "Couldn't access random device")?;
     getrandom::getrandom(&mut message_id).map_err(|_| "Couldn't access random device")?;
 
-    let ping = KRPCMessage {
-        transaction_id: &transaction_id,
-        message: KRPCMessageDetails::Query(KRPCQuery::Ping { id: &message_id }),
-    }
-    .to_bencode();
-    let addr = "127.0.0.1:6881";
-    socket.send_to(&ping, addr)?;
-    let (number_of_bytes, _) = socket.recv_from(&mut buf)?;
-    let filled_buf = &mut buf[..number_of_bytes];
-    let message = KRPCMessage::from_bencode(filled_buf)?;
+    let ping = KRPCMessage::query(KRPCQuery::Ping { id: &message_id })?;
+    transactions.register(&ping.transaction_id, PendingQuery::Ping);
+    socket.send_to(&ping.to_bencode(), BOOTSTRAP_NODE)?;
+    let message = recv_response(
+        socket,
+        &mut transactions,
+        &message_id,
+        BOOTSTRAP_NODE,
+        &mut buf,
+        RESPONSE_TIMEOUT,
+    )?;
+    error_from_response(&message, BOOTSTRAP_NODE)?;
     if let Some(ip) = ip_from_ping(&message) {
         println!("Found IP address {:?}", ip);
         println!("Node ID Calculated: {:x?}", node_id(ip));
@@ -75,35 +191,68 @@ fn bootstrap(socket: &UdpSocket) -> Result<(), Box<dyn std::error::Error>> {
     Ok(())
 }
 
-fn get_peers(socket: &UdpSocket, addr: &str) {
-    let mut buf = [0; 512];
-
-    let ping = KRPCMessage {
-        transaction_id: b"aa",
-        message: KRPCMessageDetails::Query(KRPCQuery::GetPeers {
-            id: b"abcdefghij0123456789",
-            info_hash: b"mnopqrstuvwxyz123456",
-        }),
-    }
-    .to_bencode();
-    socket.send_to(&ping, addr).unwrap();
-    let number_of_bytes = socket.recv(&mut buf).expect("Didn't receive data");
-    let filled_buf = &mut buf[..number_of_bytes];
-    println!("Retrieved {:?}", KRPCMessage::from_bencode(filled_buf));
+fn get_peers<T: Transport>(
+    socket: &T,
+    addr: SocketAddr,
+) -> Result<Vec<SocketAddrV4>, Box<dyn std::error::Error>> {
+    let mut buf = vec![0; MAX_DATAGRAM_SIZE];
+    let mut transactions = TransactionTable::default();
+    let id = b"abcdefghij0123456789";
+    let info_hash = b"mnopqrstuvwxyz123456";
+
+    let ping = KRPCMessage::query(KRPCQuery::GetPeers {
+        id,
+        info_hash,
+        want: None,
+    })?;
+    transactions.register(
+        &ping.transaction_id,
+        PendingQuery::GetPeers {
+            info_hash: *info_hash,
+        },
+    );
+    socket.send_to(&ping.to_bencode(), addr)?;
+    let message = recv_response(
+        socket,
+        &mut transactions,
+        id,
+        addr,
+        &mut buf,
+        RESPONSE_TIMEOUT,
+    )?;
+    error_from_response(&message, addr)?;
+    match message.message {
+        KRPCMessageDetails::Response(KRPCResponse::GetPeers { values, .. }) => Ok(values),
+        _ => {
+            println!("Retrieved {:?}", message);
+            Ok(vec![])
+        }
+    }
+}
+
+fn run<T: Transport>(socket: &T) {
+    if let Err(err) = bootstrap(socket) {
+        // Without a bootstrap node there is nobody to ask for peers
+        println!("Failed to bootstrap server: {}", err);
+        return;
+    }
+    // The bootstrap node is the only one we know about so far
+    match get_peers(socket, BOOTSTRAP_NODE) {
+        Ok(peers) => {
+            for peer in peers {
+                println!("Found peer {}", peer);
+            }
+        }
+        Err(err) => println!("Failed to get peers from {}: {}", BOOTSTRAP_NODE, err),
+    }
 }
 
 fn main() {
     match grab_socket() {
         Ok(socket) => {
-            let addr = format!("{}", socket.local_addr().unwrap());
+            let addr = socket.local_addr().unwrap();
             println!("Allocated socket {}", addr);
-            socket
-                .set_read_timeout(Some(Duration::new(10, 0)))
-                .expect("Can't set timout");
-            if let Err(err) = bootstrap(&socket) {
-                println!("Failed to bootstrap server: {}", err);
-            }
-            get_peers(&socket, &addr);
+            run(&socket);
         }
         Err(e) => {
             println!("Failed to connect {}", e);
@@ -115,6 +264,316 @@ fn main() {
 mod tests {
     use super::*;
     use test_case::test_case;
+    use transport::mock::MockTransport;
+
+    #[test]
+    fn test_bootstrap() {
+        let router = SocketAddr::from(([127, 0, 0, 1], 6881));
+        let mut transport = MockTransport::default();
+        transport.respond_with(
+            router,
+            b"d2:ip6:\x7c\x1f\x4b\x15\x1a\xe11:rd2:id20:mnopqrstuvwxyz123456e1:t2:aa1:y1:re",
+        );
+        assert!(bootstrap(&transport).is_ok());
+
+        let sent = transport.sent.borrow();
+        assert_eq!(sent.len(), 1);
+        assert_eq!(sent[0].1, router);
+        let query = KRPCMessage::from_bencode(&sent[0].0).unwrap();
+        assert!(matches!(
+            query.message,
+            KRPCMessageDetails::Query(KRPCQuery::Ping { .. })
+        ));
+    }
+
+    #[test]
+    fn test_bootstrap_answers_queries() {
+        let router = SocketAddr::from(([127, 0, 0, 1], 6881));
+        let peer = SocketAddr::from(([124, 31, 75, 21], 6881));
+        let mut transport = MockTransport::default();
+        transport.respond_with(
+            router,
+            b"d2:ip6:\x7c\x1f\x4b\x15\x1a\xe11:rd2:id20:mnopqrstuvwxyz123456e1:t2:aa1:y1:re",
+        );
+        // Both of these turn up before the router's response
+        transport.deliver(
+            peer,
+            b"d1:ad2:id20:abcdefghij0123456789e1:q4:ping1:t2:zz1:y1:qe",
+        );
+        // Our transaction ids are always 2 bytes, so this can't be ours
+        transport.deliver(router, b"d1:rd2:id20:mnopqrstuvwxyz123456e1:t3:zzz1:y1:re");
+        assert!(bootstrap(&transport).is_ok());
+
+        let sent = transport.sent.borrow();
+        assert_eq!(sent.len(), 2);
+        assert_eq!(sent[0].1, router);
+        assert_eq!(sent[1].1, peer);
+        let answer = KRPCMessage::from_bencode(&sent[1].0).unwrap();
+        assert_eq!(answer.transaction_id, &b"zz"[..]);
+        assert_eq!(ip_from_ping(&answer), Some(&[124, 31, 75, 21]));
+    }
+
+    #[test]
+    fn test_bootstrap_rejects_unknown_queries() {
+        let router = SocketAddr::from(([127, 0, 0, 1], 6881));
+        let peer = SocketAddr::from(([124, 31, 75, 21], 6881));
+        let mut transport = MockTransport::default();
+        transport.respond_with(router, b"d1:rd2:id20:mnopqrstuvwxyz123456e1:t2:aa1:y1:re");
+        transport.deliver(
+            peer,
+            b"d1:ad2:id20:abcdefghij01234567896:target20:mnopqrstuvwxyz123456e1:q9:find_node1:t2:zz1:y1:qe",
+        );
+        assert!(bootstrap(&transport).is_ok());
+
+        let sent = transport.sent.borrow();
+        assert_eq!(sent.len(), 2);
+        assert_eq!(sent[1].1, peer);
+        let answer = KRPCMessage::from_bencode(&sent[1].0).unwrap();
+        assert_eq!(answer.transaction_id, &b"zz"[..]);
+        assert!(matches!(
+            answer.message,
+            KRPCMessageDetails::Error(KRPCError::MethodUnknown(_))
+        ));
+    }
+
+    #[test]
+    fn test_bootstrap_read_timeout() {
+        let router = SocketAddr::from(([127, 0, 0, 1], 6881));
+        let mut transport = MockTransport::default();
+        transport.respond_with(router, b"d1:rd2:id20:mnopqrstuvwxyz123456e1:t2:aa1:y1:re");
+        assert!(bootstrap(&transport).is_ok());
+
+        // Set from what's left of the deadline, so never more than the whole
+        let timeout = transport.read_timeout.get().unwrap();
+        assert!(!timeout.is_zero() && timeout <= RESPONSE_TIMEOUT);
+    }
+
+    #[test]
+    fn test_bootstrap_skips_garbage() {
+        let router = SocketAddr::from(([127, 0, 0, 1], 6881));
+        let stranger = SocketAddr::from(([124, 31, 75, 21], 6881));
+        let mut transport = MockTransport::default();
+        transport.respond_with(router, b"d1:rd2:id20:mnopqrstuvwxyz123456e1:t2:aa1:y1:re");
+        transport.deliver(stranger, b"not bencode");
+        transport.deliver(router, b"d1:rd2:id20:e");
+        assert!(bootstrap(&transport).is_ok());
+    }
+
+    #[test]
+    fn test_recv_response_checks_source() {
+        let node = SocketAddr::from(([127, 0, 0, 1], 6881));
+        let stranger = SocketAddr::from(([124, 31, 75, 21], 6881));
+        let mut transport = MockTransport::default();
+        // Right transaction id, wrong sender
+        transport.deliver(stranger, b"d1:rd2:id20:abcdefghij0123456789e1:t2:aa1:y1:re");
+        transport.deliver(node, b"d1:rd2:id20:mnopqrstuvwxyz123456e1:t2:aa1:y1:re");
+
+        let mut transactions = TransactionTable::default();
+        transactions.register(b"aa", PendingQuery::Ping);
+        let mut buf = vec![0; MAX_DATAGRAM_SIZE];
+        let message = recv_response(
+            &transport,
+            &mut transactions,
+            b"abcdefghij0123456789",
+            node,
+            &mut buf,
+            RESPONSE_TIMEOUT,
+        )
+        .unwrap();
+        assert!(matches!(
+            message.message,
+            KRPCMessageDetails::Response(KRPCResponse::Ping { id, .. }) if id == b"mnopqrstuvwxyz123456"
+        ));
+        assert!(transactions.is_empty());
+    }
+
+    // Never goes quiet, as if someone were spamming us
+    struct Flood;
+
+    impl Transport for Flood {
+        fn send_to(&self, buf: &[u8], _: SocketAddr) -> io::Result<usize> {
+            Ok(buf.len())
+        }
+
+        fn recv_from(&self, buf: &mut [u8]) -> io::Result<(usize, SocketAddr)> {
+            let stray = b"d1:rd2:id20:mnopqrstuvwxyz123456e1:t3:zzz1:y1:re";
+            buf[..stray.len()].copy_from_slice(stray);
+            Ok((stray.len(), BOOTSTRAP_NODE))
+        }
+
+        fn set_read_timeout(&self, _: Option<Duration>) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_recv_response_deadline() {
+        let mut transactions = TransactionTable::default();
+        transactions.register(b"aa", PendingQuery::Ping);
+        let mut buf = vec![0; MAX_DATAGRAM_SIZE];
+        let err = recv_response(
+            &Flood,
+            &mut transactions,
+            b"abcdefghij0123456789",
+            BOOTSTRAP_NODE,
+            &mut buf,
+            Duration::from_millis(50),
+        )
+        .unwrap_err();
+        assert_eq!(
+            err.downcast_ref::<io::Error>().map(io::Error::kind),
+            Some(io::ErrorKind::TimedOut)
+        );
+    }
+
+    #[test]
+    fn test_bootstrap_no_response() {
+        let transport = MockTransport::default();
+        assert!(bootstrap(&transport).is_err());
+    }
+
+    #[test]
+    fn test_bootstrap_protocol_error() {
+        let router = SocketAddr::from(([127, 0, 0, 1], 6881));
+        let mut transport = MockTransport::default();
+        transport.respond_with(router, b"d1:eli203e9:Malformede1:t2:aa1:y1:ee");
+
+        let protocol_errors = PROTOCOL_ERRORS.load(Ordering::Relaxed);
+        let err = bootstrap(&transport).unwrap_err();
+        assert_eq!(
+            err.downcast_ref::<KRPCError>(),
+            Some(&KRPCError::ProtocolError("Malformed".to_string()))
+        );
+        assert!(PROTOCOL_ERRORS.load(Ordering::Relaxed) > protocol_errors);
+
+        // A timeout is an io error, not a KRPC one
+        let err = bootstrap(&MockTransport::default()).unwrap_err();
+        assert!(err.downcast_ref::<KRPCError>().is_none());
+        assert!(err.downcast_ref::<std::io::Error>().is_some());
+    }
+
+    #[test]
+    fn test_run_stops_without_bootstrap() {
+        let transport = MockTransport::default();
+        run(&transport);
+
+        // Only the bootstrap ping went out
+        let sent = transport.sent.borrow();
+        assert_eq!(sent.len(), 1);
+        let query = KRPCMessage::from_bencode(&sent[0].0).unwrap();
+        assert!(matches!(
+            query.message,
+            KRPCMessageDetails::Query(KRPCQuery::Ping { .. })
+        ));
+    }
+
+    #[test]
+    fn test_run_gets_peers_after_bootstrap() {
+        let router = SocketAddr::from(([127, 0, 0, 1], 6881));
+        let mut transport = MockTransport::default();
+        transport.respond_with(router, b"d1:rd2:id20:mnopqrstuvwxyz123456e1:t2:aa1:y1:re");
+        run(&transport);
+
+        let sent = transport.sent.borrow();
+        assert_eq!(sent.len(), 2);
+        assert_eq!(sent[1].1, router);
+        let query = KRPCMessage::from_bencode(&sent[1].0).unwrap();
+        assert!(matches!(
+            query.message,
+            KRPCMessageDetails::Query(KRPCQuery::GetPeers { .. })
+        ));
+    }
+
+    #[test]
+    fn test_get_peers_unreachable() {
+        let node = SocketAddr::from(([127, 0, 0, 1], 6881));
+        let mut transport = MockTransport::default();
+        transport.unreachable(node);
+        assert!(get_peers(&transport, node).is_err());
+    }
+
+    #[test]
+    fn test_get_peers() {
+        let node = SocketAddr::from(([127, 0, 0, 1], 6881));
+        let mut transport = MockTransport::default();
+        transport.respond_with(
+            node,
+            b"d1:rd2:id20:abcdefghij01234567895:token8:aoeusnth6:valuesl6:axje.u6:idhtnmee1:t2:aa1:y1:re",
+        );
+        assert_eq!(
+            get_peers(&transport, node).unwrap(),
+            vec![
+                SocketAddrV4::new(Ipv4Addr::new(97, 120, 106, 101), 0x2e75),
+                SocketAddrV4::new(Ipv4Addr::new(105, 100, 104, 116), 0x6e6d),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_get_peers_large_response() {
+        let node = SocketAddr::from(([127, 0, 0, 1], 6881));
+        let peers: Vec<SocketAddrV4> = (0..60)
+            .map(|i| SocketAddrV4::new(Ipv4Addr::new(10, 0, 0, i), 6881))
+            .collect();
+        let response = KRPCMessage {
+            transaction_id: std::borrow::Cow::Borrowed(b"aa"),
+            message: KRPCMessageDetails::Response(KRPCResponse::GetPeers {
+                ip: None,
+                id: b"abcdefghij0123456789",
+                token: Some(b"aoeusnth"),
+                values: peers.clone(),
+                nodes: vec![],
+                nodes6: vec![],
+            }),
+            read_only: false,
+            version: None,
+            extra: vec![],
+        }
+        .to_bencode();
+        assert!(response.len() > 512);
+
+        let mut transport = MockTransport::default();
+        transport.respond_with(node, &response);
+        assert_eq!(get_peers(&transport, node).unwrap(), peers);
+    }
+
+    #[test]
+    fn test_get_peers_transaction_ids() {
+        let node = SocketAddr::from(([127, 0, 0, 1], 6881));
+        let transport = MockTransport::default();
+        for _ in 0..8 {
+            // Nothing responds, we only care about what was sent
+            assert!(get_peers(&transport, node).is_err());
+        }
+
+        let sent = transport.sent.borrow();
+        let transaction_ids: std::collections::HashSet<Vec<u8>> = sent
+            .iter()
+            .map(|(query, _)| {
+                KRPCMessage::from_bencode(query)
+                    .unwrap()
+                    .transaction_id
+                    .to_vec()
+            })
+            .collect();
+        assert!(transaction_ids.len() > 1);
+    }
+
+    #[test]
+    fn test_respond_to_ping() {
+        let query =
+            KRPCMessage::from_bencode(b"d1:ad2:id20:abcdefghij0123456789e1:q4:ping1:t2:aa1:y1:qe")
+                .unwrap();
+        let source = Ip::V4 {
+            addr: [124, 31, 75, 21],
+            port: [0x1a, 0xe1],
+        };
+        let response = respond_to_ping(&query, b"mnopqrstuvwxyz123456", source).unwrap();
+        let encoded = response.to_bencode();
+        let decoded = KRPCMessage::from_bencode(&encoded).unwrap();
+        assert_eq!(ip_from_ping(&decoded), Some(&[124, 31, 75, 21]));
+        assert_eq!(decoded.transaction_id, &b"aa"[..]);
+    }
 
     // Test cases described in BEP 42
     #[test_case([124, 31, 75, 21], 1, [0x5f, 0xbf, 0xb8])]
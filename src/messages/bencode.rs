@@ -1,14 +1,24 @@
+use std::collections::BTreeMap;
 use std::str::from_utf8;
 use std::{error::Error, fmt};
 
-#[derive(Debug, PartialEq, Eq)]
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub enum DecodingError {
     UnknownError,
     MissingRequiredField,
     RequiredFieldOfWrongType,
     InvalidStringLength,
     InvalidInteger,
+    InvalidIdLength,
     UnexpectedEOF,
+    UnsortedKeys,
+    DuplicateKey,
+    DepthLimitExceeded,
+    IntegerOverflow,
+    ExpectedDict,
+    ExpectedList,
+    // A KRPC response whose fields don't fit any response we know
+    AmbiguousResponse,
 }
 
 impl Error for DecodingError {
@@ -21,7 +31,15 @@ impl Error for DecodingError {
             RequiredFieldOfWrongType => "",
             InvalidStringLength => "",
             InvalidInteger => "",
+            InvalidIdLength => "",
             UnexpectedEOF => "",
+            UnsortedKeys => "",
+            DuplicateKey => "",
+            DepthLimitExceeded => "",
+            IntegerOverflow => "",
+            ExpectedDict => "",
+            ExpectedList => "",
+            AmbiguousResponse => "",
         }
     }
 }
@@ -31,6 +49,53 @@ impl fmt::Display for DecodingError {
     }
 }
 
+/// Renders bytes in the same layout as `hexdump -C`. Useful for seeing what
+/// another client actually sent when a message fails to decode.
+pub fn hex_dump(bytes: &[u8]) -> String {
+    let mut out = String::new();
+    for (line, chunk) in bytes.chunks(16).enumerate() {
+        out.push_str(&format!("{:08x} ", line * 16));
+        for i in 0..16 {
+            if i % 8 == 0 {
+                out.push(' ');
+            }
+            match chunk.get(i) {
+                Some(byte) => out.push_str(&format!("{:02x} ", byte)),
+                None => out.push_str("   "),
+            }
+        }
+        out.push_str(" |");
+        out.extend(chunk.iter().map(|&byte| {
+            if byte.is_ascii_graphic() || byte == b' ' {
+                byte as char
+            } else {
+                '.'
+            }
+        }));
+        out.push_str("|\n");
+    }
+    out.push_str(&format!("{:08x}\n", bytes.len()));
+    out
+}
+
+/// Returns the exact bytes of a torrent's `info` value. The info-hash has to be
+/// computed over the original encoding, which may not survive a re-encode.
+pub fn extract_info_bytes(torrent: &[u8]) -> Result<&[u8], DecodingError> {
+    let top_level = Bencode::new(torrent);
+    top_level.as_dict()?;
+
+    let mut remaining = Bencode::with_max_depth(&torrent[1..], DEFAULT_MAX_DEPTH - 1);
+    while remaining.peek() != Some('e') {
+        let (key, value_start) = remaining.eat_str()?;
+        let (_, rest) = value_start.eat_any()?;
+        if key == b"info" {
+            return Ok(&value_start.buffer[..value_start.len() - rest.len()]);
+        }
+        remaining = rest;
+    }
+    Err(DecodingError::MissingRequiredField)
+}
+
 pub trait ToBencode {
     fn to_bencode(&self) -> Vec<u8>;
 }
@@ -39,90 +104,163 @@ pub trait FromBencode<'a>: Sized {
     fn from_bencode(serialised: &'a [u8]) -> Result<Self, DecodingError>;
 }
 
+// Plenty for anything sent over KRPC, and shallow enough that a message of
+// nested lists can't run us out of stack
+pub const DEFAULT_MAX_DEPTH: usize = 100;
+
+#[derive(Clone)]
 pub struct Bencode<'a> {
     pub buffer: &'a [u8],
+    // How many more dicts/lists can be nested inside what's left of the buffer
+    max_depth: usize,
 }
 
 impl<'a> Bencode<'a> {
+    pub fn new(buffer: &'a [u8]) -> Bencode<'a> {
+        Bencode::with_max_depth(buffer, DEFAULT_MAX_DEPTH)
+    }
+
+    pub fn with_max_depth(buffer: &'a [u8], max_depth: usize) -> Bencode<'a> {
+        Bencode { buffer, max_depth }
+    }
+
     pub fn len(&self) -> usize {
         self.buffer.len()
     }
 
+    pub fn is_empty(&self) -> bool {
+        self.buffer.is_empty()
+    }
+
     pub fn as_dict(&self) -> Result<Dict<'a>, DecodingError> {
         let (dict, leftover) = self.eat_dict()?;
-        if leftover.len() > 0 {
+        if !leftover.is_empty() {
             Err(DecodingError::UnknownError)
         } else {
             Ok(dict)
         }
     }
 
+    /// Like `as_dict`, but also rejects dicts, at any depth, whose keys aren't
+    /// in strictly ascending order. Two parsers can disagree on which of a
+    /// pair of duplicate keys wins, so we refuse to pick.
+    pub fn as_strict_dict(&self) -> Result<Dict<'a>, DecodingError> {
+        let dict = self.as_dict()?;
+        check_key_order(&Value::Dict(dict.clone()))?;
+        Ok(dict)
+    }
+
     pub fn eat_integer(&self) -> Result<(&'a [u8], Bencode<'a>), DecodingError> {
-        // TODO: Should be errors
-        assert!(self.buffer.len() >= 3);
-        assert_eq!(self.peek(), Some('i'));
+        if self.buffer.len() < 2 {
+            return Err(DecodingError::UnexpectedEOF);
+        }
+        if self.peek() != Some('i') {
+            return Err(DecodingError::InvalidInteger);
+        }
         let mut tokens = self.buffer.splitn(2, |x| *x == b'e');
-        let int = tokens.next().ok_or(DecodingError::UnexpectedEOF)?;
-        let rest_of_buffer = tokens.next().ok_or(DecodingError::UnexpectedEOF)?;
+        let int = tokens.next().ok_or(DecodingError::InvalidInteger)?;
+        let rest_of_buffer = tokens.next().ok_or(DecodingError::InvalidInteger)?;
+
+        // Bencode only allows an optional minus sign followed by digits. Zero
+        // has exactly one encoding, so no leading zeros and no `-0`
+        let body = &int[1..];
+        let digits = body.strip_prefix(b"-").unwrap_or(body);
+        if digits.is_empty()
+            || !digits.iter().all(u8::is_ascii_digit)
+            || (digits.starts_with(b"0") && body != b"0")
+        {
+            return Err(DecodingError::InvalidInteger);
+        }
         Ok((
-            &int[1..],
+            body,
             Bencode {
                 buffer: rest_of_buffer,
+                max_depth: self.max_depth,
             },
         ))
     }
 
     pub fn eat_dict(&self) -> Result<(Dict<'a>, Bencode<'a>), DecodingError> {
-        // TODO: Should be errors
-        assert!(self.buffer.len() >= 2);
-        assert_eq!(self.peek(), Some('d'));
+        if self.peek().is_some_and(|c| c != 'd') {
+            return Err(DecodingError::ExpectedDict);
+        }
+        if self.buffer.len() < 2 {
+            return Err(DecodingError::UnexpectedEOF);
+        }
+        if self.max_depth == 0 {
+            return Err(DecodingError::DepthLimitExceeded);
+        }
 
         let mut iter = Dict {
             string: Bencode {
                 buffer: &self.buffer[1..],
+                max_depth: self.max_depth - 1,
             },
+            error: None,
         };
         while iter.next().is_some() {}
+        if let Some(err) = iter.error {
+            return Err(err);
+        }
         if iter.string.peek() == Some('e') {
             Ok((
                 Dict {
                     string: Bencode {
                         buffer: &self.buffer[1..],
+                        max_depth: self.max_depth - 1,
                     },
+                    error: None,
                 },
                 Bencode {
                     buffer: &(iter.string.buffer)[1..],
+                    max_depth: self.max_depth,
                 },
             ))
         } else {
-            Err(DecodingError::UnknownError)
+            // Ran out of buffer before the closing 'e'
+            Err(DecodingError::UnexpectedEOF)
         }
     }
 
     pub fn eat_list(&self) -> Result<(List<'a>, Bencode<'a>), DecodingError> {
-        // TODO: Should be errors
-        assert!(self.buffer.len() >= 2);
-        assert_eq!(self.peek(), Some('l'));
+        if self.peek().is_some_and(|c| c != 'l') {
+            return Err(DecodingError::ExpectedList);
+        }
+        if self.buffer.len() < 2 {
+            return Err(DecodingError::UnexpectedEOF);
+        }
+        if self.max_depth == 0 {
+            return Err(DecodingError::DepthLimitExceeded);
+        }
 
         let mut iter = List {
             string: Bencode {
                 buffer: &self.buffer[1..],
+                max_depth: self.max_depth - 1,
             },
+            error: None,
         };
         while iter.next().is_some() {}
+        if let Some(err) = iter.error {
+            return Err(err);
+        }
         if iter.string.peek() == Some('e') {
             Ok((
                 List {
                     string: Bencode {
                         buffer: &self.buffer[1..],
+                        max_depth: self.max_depth - 1,
                     },
+                    error: None,
                 },
                 Bencode {
                     buffer: &(iter.string.buffer)[1..],
+                    max_depth: self.max_depth,
                 },
             ))
         } else {
-            Err(DecodingError::UnknownError)
+            // Ran out of buffer before the closing 'e'
+            Err(DecodingError::UnexpectedEOF)
         }
     }
 
@@ -130,6 +268,10 @@ impl<'a> Bencode<'a> {
         let mut tokens = self.buffer.splitn(2, |x| *x == b':');
         let key_len = tokens.next().ok_or(DecodingError::UnexpectedEOF)?;
         let rest_of_key = tokens.next().ok_or(DecodingError::UnexpectedEOF)?;
+        // parse() would also accept a leading '+'
+        if key_len.is_empty() || !key_len.iter().all(u8::is_ascii_digit) {
+            return Err(DecodingError::InvalidStringLength);
+        }
         let len_string = from_utf8(key_len)
             .ok()
             .ok_or(DecodingError::InvalidStringLength)?;
@@ -137,12 +279,16 @@ impl<'a> Bencode<'a> {
             .parse()
             .ok()
             .ok_or(DecodingError::InvalidStringLength)?;
+        if string_len > rest_of_key.len() {
+            return Err(DecodingError::UnexpectedEOF);
+        }
         let (key, rest_of_buffer) = rest_of_key.split_at(string_len);
 
         Ok((
             key,
             Bencode {
                 buffer: rest_of_buffer,
+                max_depth: self.max_depth,
             },
         ))
     }
@@ -164,16 +310,20 @@ impl<'a> Bencode<'a> {
             Some('i') => {
                 let (i, b) = self.eat_integer()?;
                 let int_string = from_utf8(i).ok().ok_or(DecodingError::InvalidInteger)?;
+                // Bencode integers are unbounded, but nothing in KRPC needs
+                // more than an i64. eat_integer already checked the digits,
+                // so the only way parsing fails is the value not fitting
                 Ok((
                     Value::Integer(
                         int_string
                             .parse()
                             .ok()
-                            .ok_or(DecodingError::InvalidInteger)?,
+                            .ok_or(DecodingError::IntegerOverflow)?,
                     ),
                     b,
                 ))
             }
+            None => Err(DecodingError::UnexpectedEOF),
             _ => Err(DecodingError::UnknownError),
         }
     }
@@ -183,6 +333,27 @@ impl<'a> Bencode<'a> {
     }
 }
 
+fn check_key_order(value: &Value) -> Result<(), DecodingError> {
+    match value {
+        Value::Dict(d) => {
+            let mut previous: Option<&[u8]> = None;
+            for kv in d.clone() {
+                match previous.map(|p| p.cmp(kv.key)) {
+                    Some(std::cmp::Ordering::Equal) => return Err(DecodingError::DuplicateKey),
+                    Some(std::cmp::Ordering::Greater) => return Err(DecodingError::UnsortedKeys),
+                    _ => (),
+                }
+                check_key_order(&kv.value)?;
+                previous = Some(kv.key);
+            }
+            Ok(())
+        }
+        Value::List(l) => l.clone().try_for_each(|v| check_key_order(&v)),
+        _ => Ok(()),
+    }
+}
+
+#[derive(Clone, PartialEq, Eq)]
 pub enum Value<'a> {
     String(&'a [u8]),
     Dict(Dict<'a>),
@@ -190,6 +361,44 @@ pub enum Value<'a> {
     Integer(i64),
 }
 
+impl<'a> Value<'a> {
+    /// Looks up `key` if this value is a dict, otherwise returns `None`.
+    pub fn get(&self, key: &[u8]) -> Option<Value<'a>> {
+        match self {
+            Self::Dict(d) => d.get(key),
+            _ => None,
+        }
+    }
+
+    /// Copies the value out of the buffer it was parsed from.
+    pub fn into_owned(self) -> OwnedValue {
+        match self {
+            Self::String(s) => OwnedValue::String(s.to_vec()),
+            Self::Integer(i) => OwnedValue::Integer(i),
+            Self::List(l) => OwnedValue::List(l.map(Value::into_owned).collect()),
+            Self::Dict(d) => {
+                let mut dict = BTreeMap::new();
+                for kv in d {
+                    // First one wins, same as Dict::get
+                    dict.entry(kv.key.to_vec())
+                        .or_insert_with(|| kv.value.into_owned());
+                }
+                OwnedValue::Dict(dict)
+            }
+        }
+    }
+}
+
+/// A `Value` that doesn't borrow from the buffer it was parsed from, for
+/// keeping around once that buffer is reused.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum OwnedValue {
+    String(Vec<u8>),
+    Dict(BTreeMap<Vec<u8>, OwnedValue>),
+    List(Vec<OwnedValue>),
+    Integer(i64),
+}
+
 impl<'a> fmt::Debug for Value<'a> {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self {
@@ -206,23 +415,61 @@ impl<'a> fmt::Debug for Value<'a> {
         }
     }
 }
-#[derive(Debug)]
+#[derive(Debug, PartialEq, Eq)]
 pub struct DictKVPair<'a> {
     pub key: &'a [u8],
     pub value: Value<'a>,
 }
 
+#[derive(Clone)]
 pub struct Dict<'a> {
     string: Bencode<'a>,
+    // Set when an entry fails to decode, so that can be told apart from
+    // reaching the end of the dict
+    error: Option<DecodingError>,
 }
 
+impl<'a> Dict<'a> {
+    /// Value of the first entry with `key`. Walks a copy so the dict itself
+    /// isn't consumed.
+    pub fn get(&self, key: &[u8]) -> Option<Value<'a>> {
+        self.clone().find(|kv| kv.key == key).map(|kv| kv.value)
+    }
+
+    pub fn get_str(&self, key: &[u8]) -> Option<&'a [u8]> {
+        match self.get(key)? {
+            Value::String(s) => Some(s),
+            _ => None,
+        }
+    }
+
+    pub fn get_int(&self, key: &[u8]) -> Option<i64> {
+        match self.get(key)? {
+            Value::Integer(i) => Some(i),
+            _ => None,
+        }
+    }
+
+    pub fn get_dict(&self, key: &[u8]) -> Option<Dict<'a>> {
+        match self.get(key)? {
+            Value::Dict(d) => Some(d),
+            _ => None,
+        }
+    }
+}
+
+// Compares the remaining entries, the underlying buffers can differ in what
+// follows the dict
+impl<'a> PartialEq for Dict<'a> {
+    fn eq(&self, other: &Self) -> bool {
+        self.clone().eq(other.clone())
+    }
+}
+impl<'a> Eq for Dict<'a> {}
+
 impl<'a> fmt::Debug for Dict<'a> {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        let copy = Dict {
-            string: Bencode {
-                buffer: self.string.buffer,
-            },
-        };
+        let copy = self.clone();
         let mut builder = f.debug_struct("");
         for kv in copy {
             let key = format!("{:?}", Value::String(kv.key));
@@ -241,30 +488,40 @@ impl<'a> Iterator for Dict<'a> {
 
         let (key, partial1) = match self.string.eat_str() {
             Ok(t) => t,
-            Err(_) => return None,
+            Err(err) => {
+                self.error = Some(err);
+                return None;
+            }
         };
         let (value, partial2) = match partial1.eat_any() {
             Ok(t) => t,
-            Err(_) => return None,
+            Err(err) => {
+                self.error = Some(err);
+                return None;
+            }
         };
         self.string = partial2;
         Some(DictKVPair { key, value })
     }
 }
 
+#[derive(Clone)]
 pub struct List<'a> {
     string: Bencode<'a>,
+    // As for Dict
+    error: Option<DecodingError>,
 }
 
+impl<'a> PartialEq for List<'a> {
+    fn eq(&self, other: &Self) -> bool {
+        self.clone().eq(other.clone())
+    }
+}
+impl<'a> Eq for List<'a> {}
+
 impl<'a> fmt::Debug for List<'a> {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        let copy = List {
-            string: Bencode {
-                buffer: self.string.buffer,
-            },
-        };
-
-        f.debug_list().entries(copy).finish()
+        f.debug_list().entries(self.clone()).finish()
     }
 }
 impl<'a> Iterator for List<'a> {
@@ -276,9 +533,369 @@ impl<'a> Iterator for List<'a> {
 
         let (value, partial) = match self.string.eat_any() {
             Ok(t) => t,
-            Err(_) => return None,
+            Err(err) => {
+                self.error = Some(err);
+                return None;
+            }
         };
         self.string = partial;
         Some(value)
     }
 }
+
+impl<'a> ToBencode for Value<'a> {
+    fn to_bencode(&self) -> Vec<u8> {
+        match self {
+            Self::String(bytes) => {
+                let mut out = format!("{}:", bytes.len()).into_bytes();
+                out.extend(*bytes);
+                out
+            }
+            Self::Integer(i) => format!("i{}e", i).into_bytes(),
+            Self::Dict(d) => d.to_bencode(),
+            Self::List(l) => l.to_bencode(),
+        }
+    }
+}
+
+impl<'a> ToBencode for Dict<'a> {
+    fn to_bencode(&self) -> Vec<u8> {
+        // The spec wants keys sorted by their raw bytes, whatever order we
+        // read them in
+        let mut entries: Vec<DictKVPair> = self.clone().collect();
+        entries.sort_by(|a, b| a.key.cmp(b.key));
+
+        let mut out = vec![b'd'];
+        for kv in entries {
+            out.extend(Value::String(kv.key).to_bencode());
+            out.extend(kv.value.to_bencode());
+        }
+        out.push(b'e');
+        out
+    }
+}
+
+impl ToBencode for OwnedValue {
+    fn to_bencode(&self) -> Vec<u8> {
+        match self {
+            Self::String(bytes) => Value::String(bytes).to_bencode(),
+            Self::Integer(i) => Value::Integer(*i).to_bencode(),
+            Self::Dict(d) => {
+                // BTreeMap already iterates in sorted key order
+                let mut out = vec![b'd'];
+                for (key, value) in d {
+                    out.extend(Value::String(key).to_bencode());
+                    out.extend(value.to_bencode());
+                }
+                out.push(b'e');
+                out
+            }
+            Self::List(l) => {
+                let mut out = vec![b'l'];
+                for value in l {
+                    out.extend(value.to_bencode());
+                }
+                out.push(b'e');
+                out
+            }
+        }
+    }
+}
+impl<'a> ToBencode for List<'a> {
+    fn to_bencode(&self) -> Vec<u8> {
+        let mut out = vec![b'l'];
+        for value in self.clone() {
+            out.extend(value.to_bencode());
+        }
+        out.push(b'e');
+        out
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::encodings::bytes_from_hex;
+    use crate::hash::sha1;
+    use test_case::test_case;
+
+    #[test]
+    fn test_hex_dump() {
+        let dump = hex_dump(b"d1:ad2:id20:abcdefghij0123456789e1:q4:ping1:t2:aa1:y1:qe");
+        assert_eq!(
+            dump,
+            "00000000  64 31 3a 61 64 32 3a 69  64 32 30 3a 61 62 63 64  |d1:ad2:id20:abcd|\n\
+             00000010  65 66 67 68 69 6a 30 31  32 33 34 35 36 37 38 39  |efghij0123456789|\n\
+             00000020  65 31 3a 71 34 3a 70 69  6e 67 31 3a 74 32 3a 61  |e1:q4:ping1:t2:a|\n\
+             00000030  61 31 3a 79 31 3a 71 65                           |a1:y1:qe|\n\
+             00000038\n"
+        );
+        assert_eq!(
+            hex_dump(b"\x00\xffa"),
+            "00000000  00 ff 61                                          |..a|\n00000003\n"
+        );
+    }
+
+    #[test]
+    fn test_extract_info_bytes() {
+        let torrent = b"d8:announce17:http://x/announce4:infod6:lengthi5e4:name1:a12:piece lengthi16384ee3:zzzi1ee";
+        assert_eq!(
+            extract_info_bytes(torrent),
+            Ok(&b"d6:lengthi5e4:name1:a12:piece lengthi16384ee"[..])
+        );
+        assert_eq!(
+            sha1(extract_info_bytes(torrent).unwrap()),
+            bytes_from_hex::<20>("a53c0091c92272e34c0966cd171367241f987a34").unwrap()
+        );
+        assert_eq!(
+            extract_info_bytes(b"d8:announce0:e"),
+            Err(DecodingError::MissingRequiredField)
+        );
+    }
+
+    #[test]
+    fn test_value_get() {
+        let (value, _) = Bencode::new(b"d4:infod6:lengthi5e4:name4:teste4:listli1eee")
+            .eat_any()
+            .unwrap();
+
+        let name = value.get(b"info").and_then(|v| v.get(b"name"));
+        assert!(matches!(name, Some(Value::String(b"test"))));
+        let length = value.get(b"info").and_then(|v| v.get(b"length"));
+        assert!(matches!(length, Some(Value::Integer(5))));
+
+        assert!(value.get(b"missing").is_none());
+        assert!(value.get(b"list").unwrap().get(b"info").is_none());
+    }
+
+    #[test]
+    fn test_value_eq() {
+        let (list1, _) = Bencode::new(b"l1:ad1:bi1eee").eat_any().unwrap();
+        let (list2, _) = Bencode::new(b"l1:ad1:bi1eeei2e").eat_any().unwrap();
+        let (list3, _) = Bencode::new(b"l1:ad1:bi2eee").eat_any().unwrap();
+        assert_eq!(list1, list2);
+        assert_ne!(list1, list3);
+    }
+
+    #[test_case(b"i12e", 12; "positive")]
+    #[test_case(b"i-12e", -12; "negative")]
+    #[test_case(b"i0e", 0; "zero")]
+    #[test_case(b"i10e", 10; "trailing zero")]
+    #[test_case(b"i9223372036854775807e", i64::MAX; "max")]
+    #[test_case(b"i-9223372036854775808e", i64::MIN; "min")]
+    fn test_eat_integer(s: &[u8], expected: i64) {
+        let value = Bencode::new(s).eat_any();
+        assert!(matches!(value, Ok((Value::Integer(v), _)) if v == expected));
+    }
+
+    #[test_case(b"i 12e"; "leading space")]
+    #[test_case(b"i+12e"; "plus sign")]
+    #[test_case(b"i12 e"; "trailing space")]
+    #[test_case(b"i--1e"; "double minus")]
+    #[test_case(b"i03e"; "leading zero")]
+    #[test_case(b"i-03e"; "negative leading zero")]
+    #[test_case(b"i-0e"; "negative zero")]
+    #[test_case(b"ie"; "empty")]
+    #[test_case(b"i-e"; "lone minus")]
+    fn test_eat_integer_invalid(s: &[u8]) {
+        let value = Bencode::new(s).eat_any();
+        assert!(matches!(value, Err(DecodingError::InvalidInteger)));
+    }
+
+    #[test_case(b"", DecodingError::UnexpectedEOF; "empty")]
+    #[test_case(b"i", DecodingError::UnexpectedEOF; "lone i")]
+    #[test_case(b"ie", DecodingError::InvalidInteger; "empty integer")]
+    #[test_case(b"i12", DecodingError::InvalidInteger; "unterminated")]
+    #[test_case(b"x12e", DecodingError::InvalidInteger; "wrong prefix")]
+    fn test_eat_integer_malformed(s: &[u8], expected: DecodingError) {
+        assert_eq!(Bencode::new(s).eat_integer().err(), Some(expected));
+    }
+
+    #[test_case(b""; "empty")]
+    #[test_case(b"d"; "lone d")]
+    #[test_case(b"l"; "lone l")]
+    #[test_case(b"i"; "lone i")]
+    #[test_case(b"ie"; "empty integer")]
+    #[test_case(b"d1:ai1"; "unterminated integer in dict")]
+    #[test_case(b"l4:spam"; "unterminated list")]
+    #[test_case(b"5:hello"; "top level string")]
+    fn test_malformed_dict_doesnt_panic(s: &[u8]) {
+        assert!(Bencode::new(s).as_dict().is_err());
+        assert!(Bencode::new(s).eat_list().is_err());
+    }
+
+    #[test]
+    fn test_dict_get() {
+        let dict = Bencode::new(b"d1:ad2:id20:abcdefghij0123456789e1:q4:ping1:t2:aa1:y1:qe")
+            .as_dict()
+            .unwrap();
+
+        assert_eq!(dict.get_str(b"q"), Some(&b"ping"[..]));
+        assert_eq!(
+            dict.get_dict(b"a").and_then(|a| a.get_str(b"id")),
+            Some(&b"abcdefghij0123456789"[..])
+        );
+        assert_eq!(dict.get_int(b"q"), None);
+        assert_eq!(dict.get_dict(b"q"), None);
+        assert_eq!(dict.get(b"missing"), None);
+
+        // Lookups don't consume the dict
+        assert_eq!(dict.count(), 4);
+        let ints = Bencode::new(b"d1:ai-5ee").as_dict().unwrap();
+        assert_eq!(ints.get_int(b"a"), Some(-5));
+        assert_eq!(ints.get_str(b"a"), None);
+    }
+
+    #[test_case(b"0:", Ok(&b""[..]); "empty string")]
+    #[test_case(b"4:spam", Ok(&b"spam"[..]); "string")]
+    #[test_case(b"500:aa", Err(DecodingError::UnexpectedEOF); "longer than buffer")]
+    #[test_case(b"+5:hello", Err(DecodingError::InvalidStringLength); "plus sign")]
+    #[test_case(b" 5:hello", Err(DecodingError::InvalidStringLength); "leading space")]
+    #[test_case(b":hello", Err(DecodingError::InvalidStringLength); "no length")]
+    fn test_eat_str(s: &[u8], expected: Result<&[u8], DecodingError>) {
+        assert_eq!(
+            Bencode::new(s).eat_str().map(|(string, _)| string),
+            expected
+        );
+    }
+
+    #[test]
+    fn test_over_long_string_in_dict() {
+        assert!(Bencode::new(b"d1:t500:aae").as_dict().is_err());
+    }
+
+    #[test_case(b"4:spam"; "string")]
+    #[test_case(b"0:"; "empty string")]
+    #[test_case(b"i-42e"; "integer")]
+    #[test_case(b"le"; "empty list")]
+    #[test_case(b"de"; "empty dict")]
+    #[test_case(
+        b"d1:ad2:id20:abcdefghij0123456789e1:lld1:xi0ee4:spame1:q4:ping1:t2:aa1:y1:qe";
+        "nested"
+    )]
+    fn test_value_round_trip(s: &[u8]) {
+        let (value, _) = Bencode::new(s).eat_any().unwrap();
+        assert_eq!(value.to_bencode(), s.to_vec());
+    }
+
+    #[test]
+    fn test_dict_to_bencode_sorts_keys() {
+        let dict = Bencode::new(b"d1:bi2e1:ai1ee").as_dict().unwrap();
+        assert_eq!(dict.to_bencode(), b"d1:ai1e1:bi2ee".to_vec());
+    }
+
+    #[test_case(b"d1:ai1e1:bi2ee", None; "sorted")]
+    #[test_case(b"d1:ai1e2:aai2ee", None; "prefix sorts first")]
+    #[test_case(b"d1:bi1e1:ai2ee", Some(DecodingError::UnsortedKeys); "unsorted")]
+    #[test_case(b"d1:ai1e1:ai2ee", Some(DecodingError::DuplicateKey); "duplicate")]
+    #[test_case(b"d1:ad1:bi1e1:ai2eee", Some(DecodingError::UnsortedKeys); "unsorted nested")]
+    #[test_case(b"d1:lld1:ai1e1:ai2eeee", Some(DecodingError::DuplicateKey); "duplicate in list")]
+    fn test_as_strict_dict(s: &[u8], expected: Option<DecodingError>) {
+        assert_eq!(Bencode::new(s).as_strict_dict().err(), expected);
+        // The lenient parser still takes all of them
+        assert!(Bencode::new(s).as_dict().is_ok());
+    }
+
+    #[test_case(b"d1:ai1e1:bi03e1:ci3ee", DecodingError::InvalidInteger; "corrupt dict value")]
+    #[test_case(b"d1:ai1e1x:i2ee", DecodingError::InvalidStringLength; "corrupt dict key")]
+    #[test_case(b"d1:ali1ei03eee", DecodingError::InvalidInteger; "corrupt nested list")]
+    #[test_case(b"d1:ai1e1:b", DecodingError::UnexpectedEOF; "truncated")]
+    fn test_corrupt_entries_propagate(s: &[u8], expected: DecodingError) {
+        assert_eq!(Bencode::new(s).as_dict().err(), Some(expected));
+    }
+
+    #[test]
+    fn test_depth_limit() {
+        let mut deep = vec![b'l'; 10_000];
+        deep.extend(vec![b'e'; 10_000]);
+        assert!(matches!(
+            Bencode::new(&deep).eat_any(),
+            Err(DecodingError::DepthLimitExceeded)
+        ));
+
+        let mut nested = b"d1:a".to_vec();
+        nested.extend(&deep);
+        nested.push(b'e');
+        assert_eq!(
+            Bencode::new(&nested).as_dict().err(),
+            Some(DecodingError::DepthLimitExceeded)
+        );
+
+        assert!(Bencode::with_max_depth(b"llee", 2).eat_any().is_ok());
+        assert!(matches!(
+            Bencode::with_max_depth(b"llee", 1).eat_any(),
+            Err(DecodingError::DepthLimitExceeded)
+        ));
+        assert!(Bencode::with_max_depth(b"i1e", 0).eat_any().is_ok());
+    }
+
+    #[test]
+    fn test_into_owned() {
+        let owned = {
+            let buffer = b"d4:infod6:lengthi5e4:name4:teste4:listli1e1:aee".to_vec();
+            let (value, _) = Bencode::new(&buffer).eat_any().unwrap();
+            value.into_owned()
+        };
+
+        let info = match &owned {
+            OwnedValue::Dict(d) => d.get(&b"info"[..]).unwrap(),
+            _ => panic!("Expected a dict"),
+        };
+        assert_eq!(
+            info,
+            &OwnedValue::Dict(BTreeMap::from([
+                (b"length".to_vec(), OwnedValue::Integer(5)),
+                (b"name".to_vec(), OwnedValue::String(b"test".to_vec())),
+            ]))
+        );
+        let list = match &owned {
+            OwnedValue::Dict(d) => d.get(&b"list"[..]).unwrap(),
+            _ => panic!("Expected a dict"),
+        };
+        assert_eq!(
+            list,
+            &OwnedValue::List(vec![
+                OwnedValue::Integer(1),
+                OwnedValue::String(b"a".to_vec())
+            ])
+        );
+    }
+
+    #[test_case(b"i-12e")]
+    #[test_case(b"4:spam")]
+    #[test_case(b"l4:spami42ee")]
+    #[test_case(b"d4:infod6:lengthi5e4:name4:teste4:listli1e1:aee")]
+    fn test_owned_value_to_bencode(s: &[u8]) {
+        let (value, _) = Bencode::new(s).eat_any().unwrap();
+        assert_eq!(value.into_owned().to_bencode(), s.to_vec());
+    }
+
+    #[test_case(b"i9223372036854775808e"; "above max")]
+    #[test_case(b"i-9223372036854775809e"; "below min")]
+    #[test_case(b"i99999999999999999999e"; "way above max")]
+    fn test_eat_integer_overflow(s: &[u8]) {
+        let value = Bencode::new(s).eat_any();
+        assert!(matches!(value, Err(DecodingError::IntegerOverflow)));
+    }
+
+    #[test_case(b"l0:e"; "list")]
+    #[test_case(b"i5e"; "integer")]
+    #[test_case(b"4:spam"; "string")]
+    #[test_case(b"l"; "truncated list")]
+    fn test_as_dict_not_a_dict(s: &[u8]) {
+        assert_eq!(
+            Bencode::new(s).as_dict().err(),
+            Some(DecodingError::ExpectedDict)
+        );
+    }
+
+    #[test_case(b"d0:0:e"; "dict")]
+    #[test_case(b"i5e"; "integer")]
+    #[test_case(b"4:spam"; "string")]
+    fn test_eat_list_not_a_list(s: &[u8]) {
+        assert_eq!(
+            Bencode::new(s).eat_list().err(),
+            Some(DecodingError::ExpectedList)
+        );
+    }
+}
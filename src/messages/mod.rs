@@ -1,15 +1,69 @@
 pub mod bencode;
 use bencode::*;
 
-#[derive(Debug, PartialEq, Eq)]
+use crate::encodings::{bytes_from_hex_vec, hex_from_bytes, EncodingError};
+use std::borrow::Cow;
+use std::collections::BTreeMap;
+use std::io::{self, BufRead};
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV4, SocketAddrV6};
+
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub enum KRPCError {
-    UnknownError(String),
+    // Any code we don't have a name for, kept so it survives a re-encode
+    UnknownError(i64, String),
     GenericError(String),
     ServerError(String),
     ProtocolError(String),
     MethodUnknown(String),
 }
 
+impl KRPCError {
+    /// Error for a code read off the wire. Codes stay as the bencode
+    /// integer (`i64`) rather than a `u16`, so a code out of `u16` range
+    /// decodes to `UnknownError` like any other unknown code instead of
+    /// failing the whole message.
+    pub fn from_code(code: i64, msg: String) -> KRPCError {
+        match code {
+            201 => KRPCError::GenericError(msg),
+            202 => KRPCError::ServerError(msg),
+            203 => KRPCError::ProtocolError(msg),
+            204 => KRPCError::MethodUnknown(msg),
+            _ => KRPCError::UnknownError(code, msg),
+        }
+    }
+
+    /// The code sent on the wire, e.g. 201 for a generic error. An
+    /// `UnknownError` gives back the code it was decoded with, out of range
+    /// or not.
+    pub fn code(&self) -> i64 {
+        match self {
+            KRPCError::UnknownError(code, _) => *code,
+            KRPCError::GenericError(_) => 201,
+            KRPCError::ServerError(_) => 202,
+            KRPCError::ProtocolError(_) => 203,
+            KRPCError::MethodUnknown(_) => 204,
+        }
+    }
+
+    pub fn message(&self) -> &str {
+        match self {
+            KRPCError::UnknownError(_, msg)
+            | KRPCError::GenericError(msg)
+            | KRPCError::ServerError(msg)
+            | KRPCError::ProtocolError(msg)
+            | KRPCError::MethodUnknown(msg) => msg,
+        }
+    }
+}
+
+impl std::error::Error for KRPCError {}
+
+impl std::fmt::Display for KRPCError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{:?}", self)
+    }
+}
+
 #[derive(Debug, PartialEq, Eq)]
 pub enum KRPCQuery<'a> {
     Ping {
@@ -18,37 +72,133 @@ pub enum KRPCQuery<'a> {
     FindNode {
         id: &'a [u8; 20],
         target: &'a [u8; 20],
+        // BEP 32. Which node families to send back. None leaves it to the
+        // responder, which sends nodes of the family the query came over
+        want: Option<Vec<AddressFamily>>,
     },
     GetPeers {
         id: &'a [u8; 20],
         info_hash: &'a [u8; 20],
+        want: Option<Vec<AddressFamily>>,
+    },
+    AnnouncePeer {
+        id: &'a [u8; 20],
+        // Use the port the query came from rather than `port`, for peers
+        // behind a NAT that don't know their external port
+        implied_port: bool,
+        info_hash: &'a [u8; 20],
+        port: u16,
+        // BEP 33. Announcer is a seed; only encoded when set
+        seed: bool,
+        token: &'a [u8],
+    },
+    // BEP 51
+    SampleInfohashes {
+        id: &'a [u8; 20],
+        target: &'a [u8; 20],
     },
 }
 
-// #[derive(Debug)]
-// struct NodeInfo<'a> {
-//     id: &'a [u8; 20],
-//     ip: &'a [u8; 4],
-//     port: &'a [u8; 2]
-// }
+impl<'a> KRPCQuery<'a> {
+    /// The announce_peer query that follows up a get_peers `response`,
+    /// replaying its token. `None` unless `response` is from get_peers and
+    /// came with a token.
+    pub fn announce_peer(
+        response: &KRPCResponse<'a>,
+        id: &'a [u8; 20],
+        info_hash: &'a [u8; 20],
+        port: u16,
+    ) -> Option<KRPCQuery<'a>> {
+        match response {
+            KRPCResponse::GetPeers {
+                token: Some(token), ..
+            } => Some(KRPCQuery::AnnouncePeer {
+                id,
+                implied_port: false,
+                info_hash,
+                port,
+                seed: false,
+                token,
+            }),
+            _ => None,
+        }
+    }
+
+    /// Address to record for an announce that arrived from `source`. With
+    /// `implied_port` set the announced port is ignored in favour of the
+    /// source port. None for anything that isn't an announce.
+    pub fn announced_peer(&self, source: SocketAddrV4) -> Option<SocketAddrV4> {
+        match self {
+            KRPCQuery::AnnouncePeer {
+                implied_port, port, ..
+            } => Some(SocketAddrV4::new(
+                *source.ip(),
+                if *implied_port { source.port() } else { *port },
+            )),
+            _ => None,
+        }
+    }
+}
+
+// Values of the BEP 32 `want` key
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AddressFamily {
+    V4,
+    V6,
+}
+
+impl AddressFamily {
+    fn as_bytes(&self) -> &'static [u8] {
+        match self {
+            AddressFamily::V4 => b"n4",
+            AddressFamily::V6 => b"n6",
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct NodeInfo<A = SocketAddrV4> {
+    pub id: [u8; 20],
+    pub addr: A,
+}
 
 #[derive(Debug, PartialEq, Eq)]
 pub enum KRPCResponse<'a> {
+    // Also the answer to announce_peer, which only carries an id too
     Ping {
-        ip: Option<Ip<'a>>,
+        ip: Option<Ip>,
         id: &'a [u8; 20],
     },
     FindNode {
-        ip: Option<Ip<'a>>,
+        ip: Option<Ip>,
         id: &'a [u8; 20],
-        // TODO: NodeInfo should go here. spec is ambiguious though, using str for now.
-        nodes: &'a [u8],
+        nodes: Vec<NodeInfo>,
+        // BEP 32
+        nodes6: Vec<NodeInfo<SocketAddrV6>>,
     },
     GetPeers {
-        ip: Option<Ip<'a>>,
+        ip: Option<Ip>,
         id: &'a [u8; 20],
-        token: &'a [u8],
-        // TODO: Values vs NodeInfo to go here
+        // Needed to announce afterwards. Nodes sending values sometimes
+        // leave it out
+        token: Option<&'a [u8]>,
+        // Peers for the info hash. Empty when the node sent nodes instead
+        values: Vec<SocketAddrV4>,
+        // Closer nodes to ask. Can come alongside values
+        nodes: Vec<NodeInfo>,
+        // BEP 32
+        nodes6: Vec<NodeInfo<SocketAddrV6>>,
+    },
+    // BEP 51
+    SampleInfohashes {
+        ip: Option<Ip>,
+        id: &'a [u8; 20],
+        // Seconds before this node should be sampled again
+        interval: i64,
+        nodes: Vec<NodeInfo>,
+        // Total number of info hashes the node has stored
+        num: i64,
+        samples: &'a [u8],
     },
 }
 
@@ -59,104 +209,397 @@ pub enum KRPCMessageDetails<'a> {
     Response(KRPCResponse<'a>),
 }
 
-#[derive(Debug, PartialEq, Eq)]
-pub enum Ip<'a> {
-    V4 {
-        addr: &'a [u8; 4],
-        port: &'a [u8; 2],
-    },
+// Compact address as sent in the BEP 42 `ip` key
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Ip {
+    V4 { addr: [u8; 4], port: [u8; 2] },
+    V6 { addr: [u8; 16], port: [u8; 2] },
+}
+
+impl From<SocketAddr> for Ip {
+    fn from(addr: SocketAddr) -> Ip {
+        match addr {
+            SocketAddr::V4(addr) => Ip::V4 {
+                addr: addr.ip().octets(),
+                port: addr.port().to_be_bytes(),
+            },
+            SocketAddr::V6(addr) => Ip::V6 {
+                addr: addr.ip().octets(),
+                port: addr.port().to_be_bytes(),
+            },
+        }
+    }
 }
 
 #[derive(Debug, PartialEq, Eq)]
 pub struct KRPCMessage<'a> {
-    pub transaction_id: &'a [u8],
+    // Borrowed when decoded, owned when we generated it
+    pub transaction_id: Cow<'a, [u8]>,
     pub message: KRPCMessageDetails<'a>,
+    // BEP 43. Sender doesn't answer queries, so shouldn't go in routing tables
+    pub read_only: bool,
+    // Client version from the `v` key, e.g. b"MC01". Opaque, but handy for
+    // seeing who we're talking to
+    pub version: Option<&'a [u8]>,
+    // Top level keys we don't understand, kept so they can be inspected or forwarded
+    pub extra: Vec<(&'a [u8], Value<'a>)>,
+}
+
+impl<'a> KRPCMessage<'a> {
+    /// Wraps `query` with a fresh random transaction id. These have to be
+    /// unpredictable, otherwise anyone off path can forge our responses.
+    pub fn query(query: KRPCQuery<'a>) -> Result<KRPCMessage<'a>, &'static str> {
+        let mut transaction_id = vec![0; 2];
+        getrandom::getrandom(&mut transaction_id).map_err(|_| "Couldn't access random device")?;
+        Ok(KRPCMessage {
+            transaction_id: Cow::Owned(transaction_id),
+            message: KRPCMessageDetails::Query(query),
+            read_only: false,
+            version: None,
+            extra: vec![],
+        })
+    }
+
+    /// Answers `query`, reusing its transaction id
+    pub fn response(query: &KRPCMessage<'a>, response: KRPCResponse<'a>) -> KRPCMessage<'a> {
+        KRPCMessage {
+            transaction_id: query.transaction_id.clone(),
+            message: KRPCMessageDetails::Response(response),
+            read_only: false,
+            version: None,
+            extra: vec![],
+        }
+    }
+
+    /// Rejects `query` with `error`, reusing its transaction id
+    pub fn error(query: &KRPCMessage<'a>, error: KRPCError) -> KRPCMessage<'a> {
+        KRPCMessage {
+            transaction_id: query.transaction_id.clone(),
+            message: KRPCMessageDetails::Error(error),
+            read_only: false,
+            version: None,
+            extra: vec![],
+        }
+    }
+
+    pub fn to_hex(&self) -> String {
+        hex_from_bytes(&self.to_bencode())
+    }
+
+    /// Turns a message logged with `to_hex` back into bencode, ready for
+    /// `from_bencode`. The message has to borrow from the bytes, so they're
+    /// handed back rather than decoded here.
+    pub fn bencode_from_hex(hex: &str) -> Result<Vec<u8>, EncodingError> {
+        bytes_from_hex_vec(hex)
+    }
 }
 
 impl<'a> ToBencode for KRPCMessage<'a> {
     fn to_bencode(&self) -> Vec<u8> {
-        // This method is dogshite. Relies on coincidence to order the
-        // encoded message correctly. Rewrite would be hard without more allocations though
-        // and it works for now
-        let mut vec1 = Vec::with_capacity(256);
-        vec1.push(b'd');
-
-        match &self.message {
-            KRPCMessageDetails::Error(err) => match err {
-                KRPCError::UnknownError(msg) => {
-                    vec1.extend(format!("1:eli201e{}:{}e", msg.len(), msg).bytes())
-                }
-                KRPCError::GenericError(msg) => {
-                    vec1.extend(format!("1:eli201e{}:{}e", msg.len(), msg).bytes())
-                }
-                KRPCError::ServerError(msg) => {
-                    vec1.extend(format!("1:eli202e{}:{}e", msg.len(), msg).bytes())
-                }
-                KRPCError::ProtocolError(msg) => {
-                    vec1.extend(format!("1:eli203e{}:{}e", msg.len(), msg).bytes())
-                }
-                KRPCError::MethodUnknown(msg) => {
-                    vec1.extend(format!("1:eli204e{}:{}e", msg.len(), msg).bytes())
-                }
-            },
-            KRPCMessageDetails::Query(q) => match q {
-                KRPCQuery::Ping { id } => {
-                    vec1.extend(b"1:ad2:id20:");
-                    vec1.extend(*id);
-                    vec1.extend(b"e1:q4:ping");
-                }
-                KRPCQuery::GetPeers { id, info_hash } => {
-                    vec1.extend(b"1:ad2:id20:");
-                    vec1.extend(*id);
-                    vec1.extend(b"9:info_hash20:");
-                    vec1.extend(*info_hash);
-                    vec1.extend(b"e1:q9:get_peers");
-                }
-                KRPCQuery::FindNode { id, target } => {
-                    vec1.extend(b"1:ad2:id20:");
-                    vec1.extend(*id);
-                    vec1.extend(b"6:target20:");
-                    vec1.extend(*target);
-                    vec1.extend(b"e1:q9:find_node");
-                }
-            },
-            KRPCMessageDetails::Response(q) => match q {
-                KRPCResponse::Ping { id, .. } => {
-                    vec1.extend(b"1:rd2:id20:");
-                    vec1.extend(*id);
-                    vec1.extend(b"e");
-                }
-                KRPCResponse::GetPeers { id, token, .. } => {
-                    vec1.extend(b"1:rd2:id20:");
-                    vec1.extend(*id);
-                    vec1.extend(format!("5:token{}:", token.len()).bytes());
-                    vec1.extend(*token);
-                    vec1.extend(b"e");
-                }
-                KRPCResponse::FindNode { id, nodes, .. } => {
-                    vec1.extend(b"1:rd2:id20:");
-                    vec1.extend(*id);
-                    vec1.extend(format!("5:nodes{}:", nodes.len()).bytes());
-                    vec1.extend(*nodes);
-                    vec1.extend(b"e");
+        // Everything goes through a BTreeMap so the keys come out in the
+        // order the spec wants, however they're added. Unknown keys go in
+        // first so they can't clobber anything we set.
+        let mut top: BTreeMap<Vec<u8>, OwnedValue> = self
+            .extra
+            .iter()
+            .map(|(key, value)| (key.to_vec(), value.clone().into_owned()))
+            .collect();
+
+        let message_type: &[u8] = match &self.message {
+            KRPCMessageDetails::Error(err) => {
+                top.insert(
+                    b"e".to_vec(),
+                    OwnedValue::List(vec![
+                        OwnedValue::Integer(err.code()),
+                        string(err.message().as_bytes()),
+                    ]),
+                );
+                b"e"
+            }
+            KRPCMessageDetails::Query(q) => {
+                let mut args = BTreeMap::new();
+                let query_type: &[u8] = match q {
+                    KRPCQuery::Ping { id } => {
+                        args.insert(b"id".to_vec(), string(*id));
+                        b"ping"
+                    }
+                    KRPCQuery::GetPeers {
+                        id,
+                        info_hash,
+                        want,
+                    } => {
+                        args.insert(b"id".to_vec(), string(*id));
+                        args.insert(b"info_hash".to_vec(), string(*info_hash));
+                        if let Some(want) = want {
+                            args.insert(b"want".to_vec(), encode_want(want));
+                        }
+                        b"get_peers"
+                    }
+                    KRPCQuery::FindNode { id, target, want } => {
+                        args.insert(b"id".to_vec(), string(*id));
+                        args.insert(b"target".to_vec(), string(*target));
+                        if let Some(want) = want {
+                            args.insert(b"want".to_vec(), encode_want(want));
+                        }
+                        b"find_node"
+                    }
+                    KRPCQuery::AnnouncePeer {
+                        id,
+                        implied_port,
+                        info_hash,
+                        port,
+                        seed,
+                        token,
+                    } => {
+                        args.insert(b"id".to_vec(), string(*id));
+                        if *implied_port {
+                            args.insert(b"implied_port".to_vec(), OwnedValue::Integer(1));
+                        }
+                        args.insert(b"info_hash".to_vec(), string(*info_hash));
+                        args.insert(b"port".to_vec(), OwnedValue::Integer(*port as i64));
+                        if *seed {
+                            args.insert(b"seed".to_vec(), OwnedValue::Integer(1));
+                        }
+                        args.insert(b"token".to_vec(), string(token));
+                        b"announce_peer"
+                    }
+                    KRPCQuery::SampleInfohashes { id, target } => {
+                        args.insert(b"id".to_vec(), string(*id));
+                        args.insert(b"target".to_vec(), string(*target));
+                        b"sample_infohashes"
+                    }
+                };
+                top.insert(b"a".to_vec(), OwnedValue::Dict(args));
+                top.insert(b"q".to_vec(), string(query_type));
+                b"q"
+            }
+            KRPCMessageDetails::Response(r) => {
+                let mut body = BTreeMap::new();
+                let ip = match r {
+                    KRPCResponse::Ping { ip, id } => {
+                        body.insert(b"id".to_vec(), string(*id));
+                        ip
+                    }
+                    KRPCResponse::GetPeers {
+                        ip,
+                        id,
+                        token,
+                        values,
+                        nodes,
+                        nodes6,
+                    } => {
+                        body.insert(b"id".to_vec(), string(*id));
+                        if let Some(token) = token {
+                            body.insert(b"token".to_vec(), string(token));
+                        }
+                        if !values.is_empty() {
+                            body.insert(
+                                b"values".to_vec(),
+                                OwnedValue::List(
+                                    values
+                                        .iter()
+                                        .map(|peer| {
+                                            OwnedValue::String(encode_compact_peers(&[*peer]))
+                                        })
+                                        .collect(),
+                                ),
+                            );
+                        }
+                        if !nodes.is_empty() {
+                            body.insert(
+                                b"nodes".to_vec(),
+                                OwnedValue::String(encode_compact_nodes(nodes)),
+                            );
+                        }
+                        if !nodes6.is_empty() {
+                            body.insert(
+                                b"nodes6".to_vec(),
+                                OwnedValue::String(encode_compact_nodes6(nodes6)),
+                            );
+                        }
+                        ip
+                    }
+                    KRPCResponse::FindNode {
+                        ip,
+                        id,
+                        nodes,
+                        nodes6,
+                    } => {
+                        body.insert(b"id".to_vec(), string(*id));
+                        // An IPv6 only response can leave out nodes entirely
+                        if !nodes.is_empty() || nodes6.is_empty() {
+                            body.insert(
+                                b"nodes".to_vec(),
+                                OwnedValue::String(encode_compact_nodes(nodes)),
+                            );
+                        }
+                        if !nodes6.is_empty() {
+                            body.insert(
+                                b"nodes6".to_vec(),
+                                OwnedValue::String(encode_compact_nodes6(nodes6)),
+                            );
+                        }
+                        ip
+                    }
+                    KRPCResponse::SampleInfohashes {
+                        ip,
+                        id,
+                        interval,
+                        nodes,
+                        num,
+                        samples,
+                    } => {
+                        body.insert(b"id".to_vec(), string(*id));
+                        body.insert(b"interval".to_vec(), OwnedValue::Integer(*interval));
+                        body.insert(
+                            b"nodes".to_vec(),
+                            OwnedValue::String(encode_compact_nodes(nodes)),
+                        );
+                        body.insert(b"num".to_vec(), OwnedValue::Integer(*num));
+                        body.insert(b"samples".to_vec(), string(samples));
+                        ip
+                    }
+                };
+                // BEP 42
+                match ip {
+                    Some(Ip::V4 { addr, port }) => {
+                        top.insert(b"ip".to_vec(), string(&[&addr[..], port].concat()));
+                    }
+                    Some(Ip::V6 { addr, port }) => {
+                        top.insert(b"ip".to_vec(), string(&[&addr[..], port].concat()));
+                    }
+                    None => (),
                 }
-            },
+                top.insert(b"r".to_vec(), OwnedValue::Dict(body));
+                b"r"
+            }
         };
 
-        vec1.extend(format!("1:t{}:", self.transaction_id.len()).bytes());
-        vec1.extend(self.transaction_id);
+        if self.read_only {
+            top.insert(b"ro".to_vec(), OwnedValue::Integer(1));
+        }
+        top.insert(b"t".to_vec(), string(&self.transaction_id));
+        if let Some(version) = self.version {
+            top.insert(b"v".to_vec(), string(version));
+        }
+        top.insert(b"y".to_vec(), string(message_type));
 
-        let message_type = match self.message {
-            KRPCMessageDetails::Error(_) => b'e',
-            KRPCMessageDetails::Query(_) => b'q',
-            KRPCMessageDetails::Response(_) => b'r',
-        };
-        vec1.extend(b"1:y1:");
-        vec1.push(message_type);
+        OwnedValue::Dict(top).to_bencode()
+    }
+}
+
+fn string(bytes: &[u8]) -> OwnedValue {
+    OwnedValue::String(bytes.to_vec())
+}
 
-        vec1.push(b'e');
-        vec1
+fn encode_want(want: &[AddressFamily]) -> OwnedValue {
+    OwnedValue::List(
+        want.iter()
+            .map(|family| string(family.as_bytes()))
+            .collect(),
+    )
+}
+
+/// Encodes peers in the compact form used by get_peers `values`: a 4 byte IP
+/// followed by a 2 byte big endian port for each peer.
+pub fn encode_compact_peers(peers: &[SocketAddrV4]) -> Vec<u8> {
+    let mut out = Vec::with_capacity(peers.len() * 6);
+    for peer in peers {
+        out.extend(peer.ip().octets());
+        out.extend(peer.port().to_be_bytes());
     }
+    out
+}
+
+/// Inverse of `encode_compact_peers`. Fails if `compact` isn't a whole number
+/// of peers.
+pub fn decode_compact_peers(compact: &[u8]) -> Result<Vec<SocketAddrV4>, DecodingError> {
+    if !compact.len().is_multiple_of(6) {
+        return Err(DecodingError::RequiredFieldOfWrongType);
+    }
+    Ok(compact
+        .chunks(6)
+        .map(|peer| {
+            SocketAddrV4::new(
+                Ipv4Addr::new(peer[0], peer[1], peer[2], peer[3]),
+                u16::from_be_bytes([peer[4], peer[5]]),
+            )
+        })
+        .collect())
+}
+
+/// Encodes nodes in the compact form used by `nodes`: the 20 byte node id
+/// followed by the node's compact address.
+pub fn encode_compact_nodes(nodes: &[NodeInfo]) -> Vec<u8> {
+    let mut out = Vec::with_capacity(nodes.len() * 26);
+    for node in nodes {
+        out.extend(node.id);
+        out.extend(encode_compact_peers(&[node.addr]));
+    }
+    out
+}
+
+/// Inverse of `encode_compact_nodes`. Fails if `compact` isn't a whole number
+/// of nodes.
+pub fn decode_compact_nodes(compact: &[u8]) -> Result<Vec<NodeInfo>, DecodingError> {
+    if !compact.len().is_multiple_of(26) {
+        return Err(DecodingError::RequiredFieldOfWrongType);
+    }
+    compact
+        .chunks(26)
+        .map(|node| {
+            Ok(NodeInfo {
+                id: *to_id(&node[..20])?,
+                addr: decode_compact_peers(&node[20..])?[0],
+            })
+        })
+        .collect()
+}
+
+/// IPv6 version of `encode_compact_nodes`, as used by `nodes6`: the node id
+/// followed by a 16 byte IP and 2 byte port.
+pub fn encode_compact_nodes6(nodes: &[NodeInfo<SocketAddrV6>]) -> Vec<u8> {
+    let mut out = Vec::with_capacity(nodes.len() * 38);
+    for node in nodes {
+        out.extend(node.id);
+        out.extend(node.addr.ip().octets());
+        out.extend(node.addr.port().to_be_bytes());
+    }
+    out
+}
+
+/// Inverse of `encode_compact_nodes6`
+pub fn decode_compact_nodes6(compact: &[u8]) -> Result<Vec<NodeInfo<SocketAddrV6>>, DecodingError> {
+    if !compact.len().is_multiple_of(38) {
+        return Err(DecodingError::RequiredFieldOfWrongType);
+    }
+    compact
+        .chunks(38)
+        .map(|node| {
+            Ok(NodeInfo {
+                id: *to_id(&node[..20])?,
+                addr: SocketAddrV6::new(
+                    Ipv6Addr::from(*to_fixed::<16>(&node[20..36]).unwrap()),
+                    u16::from_be_bytes([node[36], node[37]]),
+                    0,
+                    0,
+                ),
+            })
+        })
+        .collect()
+}
+
+/// Reads messages logged one per line as hex, skipping blank lines. Each item
+/// is the raw message, ready for `KRPCMessage::from_bencode`.
+pub fn read_hex_lines<R: BufRead>(reader: R) -> impl Iterator<Item = io::Result<Vec<u8>>> {
+    reader.lines().filter_map(|line| match line {
+        Ok(line) if line.trim().is_empty() => None,
+        Ok(line) => Some(
+            bytes_from_hex_vec(line.trim())
+                .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, format!("{:?}", err))),
+        ),
+        Err(err) => Some(Err(err)),
+    })
 }
 
 fn to_fixed<const N: usize>(i: &[u8]) -> Option<&[u8; N]> {
@@ -167,6 +610,26 @@ fn to_fixed<const N: usize>(i: &[u8]) -> Option<&[u8; N]> {
     }
 }
 
+fn to_id(i: &[u8]) -> Result<&[u8; 20], DecodingError> {
+    to_fixed::<20>(i).ok_or(DecodingError::InvalidIdLength)
+}
+
+fn to_ip(i: &[u8]) -> Result<Ip, DecodingError> {
+    let (addr, port) = i.split_at(i.len().saturating_sub(2));
+    let port = *to_fixed::<2>(port).ok_or(DecodingError::RequiredFieldOfWrongType)?;
+    match addr.len() {
+        4 => Ok(Ip::V4 {
+            addr: *to_fixed::<4>(addr).unwrap(),
+            port,
+        }),
+        16 => Ok(Ip::V6 {
+            addr: *to_fixed::<16>(addr).unwrap(),
+            port,
+        }),
+        _ => Err(DecodingError::RequiredFieldOfWrongType),
+    }
+}
+
 impl<'a> FromBencode<'a> for KRPCMessage<'a> {
     fn from_bencode(serialised: &'a [u8]) -> Result<KRPCMessage, DecodingError> {
         // eww
@@ -181,7 +644,8 @@ impl<'a> FromBencode<'a> for KRPCMessage<'a> {
             Ping,
             FindNode,
             GetPeers,
-            // AnnouncePeer,
+            AnnouncePeer,
+            SampleInfohashes,
             Unknown,
         }
 
@@ -193,21 +657,44 @@ impl<'a> FromBencode<'a> for KRPCMessage<'a> {
         let mut target: Option<&[u8; 20]> = None;
         let mut token: Option<&[u8]> = None;
         let mut nodes: Option<&[u8]> = None;
-        let mut ip: Option<&[u8; 6]> = None;
+        let mut nodes6: Option<&[u8]> = None;
+        let mut want: Option<Vec<AddressFamily>> = None;
+        let mut interval: Option<i64> = None;
+        let mut num: Option<i64> = None;
+        let mut samples: Option<&[u8]> = None;
+        let mut values: Vec<SocketAddrV4> = Vec::new();
+        // values can be present but empty
+        let mut has_values = false;
+        let mut port: Option<u16> = None;
+        let mut seed = false;
+        let mut implied_port = false;
+        let mut ip: Option<Ip> = None;
+
+        let mut read_only = false;
+        let mut version: Option<&[u8]> = None;
+        let mut extra = Vec::new();
 
         let mut error_details: Option<KRPCError> = None;
-        let top_level = Bencode { buffer: serialised }.as_dict()?;
+        let top_level = Bencode::new(serialised).as_dict()?;
 
         for kv in top_level {
             match kv.key {
                 b"ip" => match kv.value {
-                    Value::String(v) => ip = to_fixed::<6>(v),
+                    Value::String(v) => ip = Some(to_ip(v)?),
                     _ => return Err(DecodingError::RequiredFieldOfWrongType),
                 },
                 b"t" => match kv.value {
                     Value::String(v) => transaction_id = Some(v),
                     _ => return Err(DecodingError::RequiredFieldOfWrongType),
                 },
+                b"ro" => match kv.value {
+                    Value::Integer(ro) => read_only = ro != 0,
+                    _ => return Err(DecodingError::RequiredFieldOfWrongType),
+                },
+                b"v" => match kv.value {
+                    Value::String(v) => version = Some(v),
+                    _ => return Err(DecodingError::RequiredFieldOfWrongType),
+                },
                 b"y" => match kv.value {
                     Value::String(b"e") => message_type = MessageType::Error,
                     Value::String(b"q") => message_type = MessageType::Query,
@@ -219,8 +706,8 @@ impl<'a> FromBencode<'a> for KRPCMessage<'a> {
                         let raw_code = list.next();
                         let raw_message = list.next();
 
-                        let code: u8 = match raw_code {
-                            Some(Value::Integer(v)) => v as u8,
+                        let code = match raw_code {
+                            Some(Value::Integer(v)) => v,
                             _ => return Err(DecodingError::RequiredFieldOfWrongType),
                         };
                         let message: String = match raw_message {
@@ -228,13 +715,7 @@ impl<'a> FromBencode<'a> for KRPCMessage<'a> {
                                 .map_err(|_| DecodingError::RequiredFieldOfWrongType)?,
                             _ => return Err(DecodingError::RequiredFieldOfWrongType),
                         };
-                        error_details = Some(match code {
-                            201 => KRPCError::GenericError(message),
-                            202 => KRPCError::ServerError(message),
-                            203 => KRPCError::ProtocolError(message),
-                            204 => KRPCError::MethodUnknown(message),
-                            _ => KRPCError::UnknownError(message),
-                        });
+                        error_details = Some(KRPCError::from_code(code, message));
                     }
                     _ => return Err(DecodingError::RequiredFieldOfWrongType),
                 },
@@ -242,7 +723,8 @@ impl<'a> FromBencode<'a> for KRPCMessage<'a> {
                     Value::String(b"ping") => query_type = QueryType::Ping,
                     Value::String(b"find_node") => query_type = QueryType::FindNode,
                     Value::String(b"get_peers") => query_type = QueryType::GetPeers,
-                    Value::String(b"announce_peer") => query_type = QueryType::GetPeers,
+                    Value::String(b"announce_peer") => query_type = QueryType::AnnouncePeer,
+                    Value::String(b"sample_infohashes") => query_type = QueryType::SampleInfohashes,
                     _ => return Err(DecodingError::RequiredFieldOfWrongType),
                 },
                 b"r" => match kv.value {
@@ -250,7 +732,7 @@ impl<'a> FromBencode<'a> for KRPCMessage<'a> {
                         for qdkv in mid {
                             match qdkv.key {
                                 b"id" => match qdkv.value {
-                                    Value::String(id) => other_id = to_fixed::<20>(id),
+                                    Value::String(id) => other_id = Some(to_id(id)?),
                                     _ => return Err(DecodingError::RequiredFieldOfWrongType),
                                 },
                                 b"token" => match qdkv.value {
@@ -261,6 +743,46 @@ impl<'a> FromBencode<'a> for KRPCMessage<'a> {
                                     Value::String(n) => nodes = Some(n),
                                     _ => return Err(DecodingError::RequiredFieldOfWrongType),
                                 },
+                                b"nodes6" => match qdkv.value {
+                                    Value::String(n) => nodes6 = Some(n),
+                                    _ => return Err(DecodingError::RequiredFieldOfWrongType),
+                                },
+                                b"interval" => match qdkv.value {
+                                    Value::Integer(i) => interval = Some(i),
+                                    _ => return Err(DecodingError::RequiredFieldOfWrongType),
+                                },
+                                b"num" => match qdkv.value {
+                                    Value::Integer(n) => num = Some(n),
+                                    _ => return Err(DecodingError::RequiredFieldOfWrongType),
+                                },
+                                b"samples" => match qdkv.value {
+                                    Value::String(s) => samples = Some(s),
+                                    _ => return Err(DecodingError::RequiredFieldOfWrongType),
+                                },
+                                b"values" => match qdkv.value {
+                                    Value::List(list) => {
+                                        has_values = true;
+                                        // Each entry should be one peer, but
+                                        // accept several packed together. A
+                                        // malformed entry is skipped rather
+                                        // than losing the rest
+                                        for value in list {
+                                            match value {
+                                                Value::String(v) => {
+                                                    if let Ok(peers) = decode_compact_peers(v) {
+                                                        values.extend(peers)
+                                                    }
+                                                }
+                                                _ => {
+                                                    return Err(
+                                                        DecodingError::RequiredFieldOfWrongType,
+                                                    )
+                                                }
+                                            }
+                                        }
+                                    }
+                                    _ => return Err(DecodingError::RequiredFieldOfWrongType),
+                                },
                                 _ => (),
                             }
                         }
@@ -272,15 +794,60 @@ impl<'a> FromBencode<'a> for KRPCMessage<'a> {
                         for qdkv in mid {
                             match qdkv.key {
                                 b"id" => match qdkv.value {
-                                    Value::String(id) => other_id = to_fixed::<20>(id),
+                                    Value::String(id) => other_id = Some(to_id(id)?),
                                     _ => return Err(DecodingError::RequiredFieldOfWrongType),
                                 },
                                 b"info_hash" => match qdkv.value {
-                                    Value::String(id) => info_hash = to_fixed::<20>(id),
+                                    Value::String(id) => info_hash = Some(to_id(id)?),
                                     _ => return Err(DecodingError::RequiredFieldOfWrongType),
                                 },
                                 b"target" => match qdkv.value {
-                                    Value::String(id) => target = to_fixed::<20>(id),
+                                    Value::String(id) => target = Some(to_id(id)?),
+                                    _ => return Err(DecodingError::RequiredFieldOfWrongType),
+                                },
+                                b"port" => match qdkv.value {
+                                    Value::Integer(p) => {
+                                        port =
+                                            Some(p.try_into().map_err(|_| {
+                                                DecodingError::RequiredFieldOfWrongType
+                                            })?)
+                                    }
+                                    _ => return Err(DecodingError::RequiredFieldOfWrongType),
+                                },
+                                b"implied_port" => match qdkv.value {
+                                    Value::Integer(i) => implied_port = i != 0,
+                                    _ => return Err(DecodingError::RequiredFieldOfWrongType),
+                                },
+                                b"seed" => match qdkv.value {
+                                    Value::Integer(s) => seed = s != 0,
+                                    _ => return Err(DecodingError::RequiredFieldOfWrongType),
+                                },
+                                b"token" => match qdkv.value {
+                                    Value::String(t) => token = Some(t),
+                                    _ => return Err(DecodingError::RequiredFieldOfWrongType),
+                                },
+                                b"want" => match qdkv.value {
+                                    Value::List(list) => {
+                                        let mut families = Vec::new();
+                                        for value in list {
+                                            match value {
+                                                Value::String(b"n4") => {
+                                                    families.push(AddressFamily::V4)
+                                                }
+                                                Value::String(b"n6") => {
+                                                    families.push(AddressFamily::V6)
+                                                }
+                                                // Ignore families from future extensions
+                                                Value::String(_) => (),
+                                                _ => {
+                                                    return Err(
+                                                        DecodingError::RequiredFieldOfWrongType,
+                                                    )
+                                                }
+                                            }
+                                        }
+                                        want = Some(families);
+                                    }
                                     _ => return Err(DecodingError::RequiredFieldOfWrongType),
                                 },
                                 _ => (),
@@ -289,17 +856,14 @@ impl<'a> FromBencode<'a> for KRPCMessage<'a> {
                     }
                     _ => return Err(DecodingError::RequiredFieldOfWrongType),
                 },
-                _ => (),
+                _ => extra.push((kv.key, kv.value)),
             }
         }
 
-        let ip = ip.map(|bytes| Ip::V4 {
-            addr: to_fixed::<4>(&bytes[0..4]).unwrap(),
-            port: to_fixed::<2>(&bytes[4..]).unwrap(),
-        });
-
         Ok(KRPCMessage {
-            transaction_id: transaction_id.ok_or(DecodingError::MissingRequiredField)?,
+            transaction_id: Cow::Borrowed(
+                transaction_id.ok_or(DecodingError::MissingRequiredField)?,
+            ),
             message: match message_type {
                 MessageType::Error => KRPCMessageDetails::Error(
                     error_details.ok_or(DecodingError::MissingRequiredField)?,
@@ -311,27 +875,61 @@ impl<'a> FromBencode<'a> for KRPCMessage<'a> {
                     QueryType::GetPeers => KRPCQuery::GetPeers {
                         id: other_id.ok_or(DecodingError::MissingRequiredField)?,
                         info_hash: info_hash.ok_or(DecodingError::MissingRequiredField)?,
+                        want,
                     },
                     QueryType::FindNode => KRPCQuery::FindNode {
                         id: other_id.ok_or(DecodingError::MissingRequiredField)?,
                         target: target.ok_or(DecodingError::MissingRequiredField)?,
+                        want,
+                    },
+                    QueryType::AnnouncePeer => KRPCQuery::AnnouncePeer {
+                        id: other_id.ok_or(DecodingError::MissingRequiredField)?,
+                        implied_port,
+                        info_hash: info_hash.ok_or(DecodingError::MissingRequiredField)?,
+                        port: port.ok_or(DecodingError::MissingRequiredField)?,
+                        seed,
+                        token: token.ok_or(DecodingError::MissingRequiredField)?,
+                    },
+                    QueryType::SampleInfohashes => KRPCQuery::SampleInfohashes {
+                        id: other_id.ok_or(DecodingError::MissingRequiredField)?,
+                        target: target.ok_or(DecodingError::MissingRequiredField)?,
                     },
                     _ => return Err(DecodingError::MissingRequiredField),
                 }),
                 MessageType::Response => {
                     // We have no way of explicitly knowing what we should be
                     // deserialising to. Infer from fields
-                    let response = if let Some(unwrapped_token) = token {
+                    let response = if let Some(unwrapped_samples) = samples {
+                        KRPCResponse::SampleInfohashes {
+                            ip,
+                            id: other_id.ok_or(DecodingError::MissingRequiredField)?,
+                            interval: interval.ok_or(DecodingError::MissingRequiredField)?,
+                            nodes: decode_compact_nodes(
+                                nodes.ok_or(DecodingError::MissingRequiredField)?,
+                            )?,
+                            num: num.ok_or(DecodingError::MissingRequiredField)?,
+                            samples: unwrapped_samples,
+                        }
+                    } else if token.is_none() && has_values && (nodes.is_some() || nodes6.is_some())
+                    {
+                        // Without a token this could be either, and whichever
+                        // we guess loses the other half
+                        return Err(DecodingError::AmbiguousResponse);
+                    } else if token.is_some() || has_values {
                         KRPCResponse::GetPeers {
                             ip,
                             id: other_id.ok_or(DecodingError::MissingRequiredField)?,
-                            token: unwrapped_token,
+                            token,
+                            values,
+                            nodes: decode_compact_nodes(nodes.unwrap_or_default())?,
+                            nodes6: decode_compact_nodes6(nodes6.unwrap_or_default())?,
                         }
-                    } else if let Some(unwrapped_nodes) = nodes {
+                    } else if nodes.is_some() || nodes6.is_some() {
                         KRPCResponse::FindNode {
                             ip,
                             id: other_id.ok_or(DecodingError::MissingRequiredField)?,
-                            nodes: unwrapped_nodes,
+                            nodes: decode_compact_nodes(nodes.unwrap_or_default())?,
+                            nodes6: decode_compact_nodes6(nodes6.unwrap_or_default())?,
                         }
                     } else if let Some(unwrapped_id) = other_id {
                         KRPCResponse::Ping {
@@ -339,12 +937,15 @@ impl<'a> FromBencode<'a> for KRPCMessage<'a> {
                             id: unwrapped_id,
                         }
                     } else {
-                        return Err(DecodingError::MissingRequiredField);
+                        return Err(DecodingError::AmbiguousResponse);
                     };
                     KRPCMessageDetails::Response(response)
                 }
                 _ => return Err(DecodingError::MissingRequiredField),
             },
+            read_only,
+            version,
+            extra,
         })
     }
 }
@@ -352,27 +953,280 @@ impl<'a> FromBencode<'a> for KRPCMessage<'a> {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use test_case::test_case;
+
+    #[test]
+    fn compact_peers() {
+        let peers = [
+            SocketAddrV4::new(Ipv4Addr::new(127, 0, 0, 1), 6881),
+            SocketAddrV4::new(Ipv4Addr::new(10, 20, 30, 40), 1),
+        ];
+        assert_eq!(
+            encode_compact_peers(&peers),
+            vec![127, 0, 0, 1, 0x1a, 0xe1, 10, 20, 30, 40, 0, 1]
+        );
+        assert_eq!(encode_compact_peers(&[]), vec![]);
+
+        assert_eq!(
+            decode_compact_peers(&encode_compact_peers(&peers)),
+            Ok(peers.to_vec())
+        );
+        assert_eq!(decode_compact_peers(&[]), Ok(vec![]));
+        assert_eq!(
+            decode_compact_peers(&[127, 0, 0, 1, 0x1a]),
+            Err(DecodingError::RequiredFieldOfWrongType)
+        );
+    }
+
+    #[test]
+    fn compact_nodes() {
+        let nodes =
+            decode_compact_nodes(b"mnopqrstuvwxyz123456axje.uabcdefghij0123456789idhtnm").unwrap();
+        assert_eq!(
+            nodes,
+            vec![
+                NodeInfo {
+                    id: *b"mnopqrstuvwxyz123456",
+                    addr: SocketAddrV4::new(Ipv4Addr::new(97, 120, 106, 101), 0x2e75),
+                },
+                NodeInfo {
+                    id: *b"abcdefghij0123456789",
+                    addr: SocketAddrV4::new(Ipv4Addr::new(105, 100, 104, 116), 0x6e6d),
+                },
+            ]
+        );
+        assert_eq!(decode_compact_nodes(b""), Ok(vec![]));
+        assert_eq!(
+            decode_compact_nodes(b"def456..."),
+            Err(DecodingError::RequiredFieldOfWrongType)
+        );
+    }
+
+    #[test]
+    fn compact_nodes6() {
+        let nodes = vec![NodeInfo {
+            id: *b"mnopqrstuvwxyz123456",
+            addr: SocketAddrV6::new(Ipv6Addr::new(0x2001, 0xdb8, 0, 0, 0, 0, 0, 1), 6881, 0, 0),
+        }];
+        let compact = encode_compact_nodes6(&nodes);
+        assert_eq!(compact.len(), 38);
+        assert_eq!(&compact[20..24], &[0x20, 0x01, 0x0d, 0xb8]);
+        assert_eq!(&compact[36..], &[0x1a, 0xe1]);
+        assert_eq!(decode_compact_nodes6(&compact), Ok(nodes));
+        assert_eq!(decode_compact_nodes6(b""), Ok(vec![]));
+        assert_eq!(
+            decode_compact_nodes6(&compact[..26]),
+            Err(DecodingError::RequiredFieldOfWrongType)
+        );
+    }
+
+    #[test]
+    fn want_both_families() {
+        let find_node_query = KRPCMessage {
+            transaction_id: Cow::Borrowed(b"aa"),
+            message: KRPCMessageDetails::Query(KRPCQuery::FindNode {
+                id: b"abcdefghij0123456789",
+                target: b"mnopqrstuvwxyz123456",
+                want: Some(vec![AddressFamily::V4, AddressFamily::V6]),
+            }),
+            read_only: false,
+            version: None,
+            extra: vec![],
+        };
+        let find_node_query_encoded = b"d1:ad2:id20:abcdefghij01234567896:target20:mnopqrstuvwxyz1234564:wantl2:n42:n6ee1:q9:find_node1:t2:aa1:y1:qe";
+        assert_eq!(
+            find_node_query.to_bencode(),
+            find_node_query_encoded.to_vec()
+        );
+        assert_eq!(
+            KRPCMessage::from_bencode(find_node_query_encoded),
+            Ok(find_node_query)
+        );
+
+        let get_peers_query = KRPCMessage {
+            transaction_id: Cow::Borrowed(b"aa"),
+            message: KRPCMessageDetails::Query(KRPCQuery::GetPeers {
+                id: b"abcdefghij0123456789",
+                info_hash: b"mnopqrstuvwxyz123456",
+                want: Some(vec![AddressFamily::V4, AddressFamily::V6]),
+            }),
+            read_only: false,
+            version: None,
+            extra: vec![],
+        };
+        let get_peers_query_encoded = b"d1:ad2:id20:abcdefghij01234567899:info_hash20:mnopqrstuvwxyz1234564:wantl2:n42:n6ee1:q9:get_peers1:t2:aa1:y1:qe";
+        assert_eq!(
+            get_peers_query.to_bencode(),
+            get_peers_query_encoded.to_vec()
+        );
+        assert_eq!(
+            KRPCMessage::from_bencode(get_peers_query_encoded),
+            Ok(get_peers_query)
+        );
+
+        let mut nodes6_encoded = b"d1:rd2:id20:0123456789abcdefghij5:nodes26:mnopqrstuvwxyz123456axje.u6:nodes638:abcdefghij0123456789".to_vec();
+        nodes6_encoded.extend(Ipv6Addr::LOCALHOST.octets());
+        nodes6_encoded.extend(b"\x1a\xe1e1:t2:aa1:y1:re");
+        let response = KRPCMessage::from_bencode(&nodes6_encoded).unwrap();
+        assert_eq!(
+            response.message,
+            KRPCMessageDetails::Response(KRPCResponse::FindNode {
+                ip: None,
+                id: b"0123456789abcdefghij",
+                nodes: vec![NodeInfo {
+                    id: *b"mnopqrstuvwxyz123456",
+                    addr: SocketAddrV4::new(Ipv4Addr::new(97, 120, 106, 101), 0x2e75),
+                }],
+                nodes6: vec![NodeInfo {
+                    id: *b"abcdefghij0123456789",
+                    addr: SocketAddrV6::new(Ipv6Addr::LOCALHOST, 6881, 0, 0),
+                }],
+            })
+        );
+        assert_eq!(response.to_bencode(), nodes6_encoded);
+
+        // Unknown families are skipped
+        let future = KRPCMessage::from_bencode(b"d1:ad2:id20:abcdefghij01234567896:target20:mnopqrstuvwxyz1234564:wantl2:n62:n8ee1:q9:find_node1:t2:aa1:y1:qe").unwrap();
+        assert!(matches!(
+            future.message,
+            KRPCMessageDetails::Query(KRPCQuery::FindNode { want: Some(w), .. }) if w == vec![AddressFamily::V6]
+        ));
+    }
+
+    #[test]
+    fn get_peers_values() {
+        let empty = KRPCMessage::from_bencode(
+            b"d1:rd2:id20:abcdefghij01234567895:token8:aoeusnth6:valueslee1:t2:aa1:y1:re",
+        )
+        .unwrap();
+        assert!(matches!(
+            empty.message,
+            KRPCMessageDetails::Response(KRPCResponse::GetPeers { values, .. }) if values.is_empty()
+        ));
+
+        let short_value = KRPCMessage::from_bencode(
+            b"d1:rd2:id20:abcdefghij01234567895:token8:aoeusnth6:valuesl5:axje.6:idhtnmee1:t2:aa1:y1:re",
+        )
+        .unwrap();
+        assert!(matches!(
+            short_value.message,
+            KRPCMessageDetails::Response(KRPCResponse::GetPeers { values, .. })
+                if values == vec![SocketAddrV4::new(Ipv4Addr::new(105, 100, 104, 116), 0x6e6d)]
+        ));
+        // The 5 byte peer on its own is still rejected by the strict decoder
+        assert_eq!(
+            decode_compact_peers(b"axje."),
+            Err(DecodingError::RequiredFieldOfWrongType)
+        );
+
+        let not_a_string = KRPCMessage::from_bencode(
+            b"d1:rd2:id20:abcdefghij01234567895:token8:aoeusnth6:valuesli1eee1:t2:aa1:y1:re",
+        );
+        assert_eq!(not_a_string, Err(DecodingError::RequiredFieldOfWrongType));
+    }
+
+    #[test]
+    fn get_peers_values_only() {
+        let encoded = b"d1:rd2:id20:abcdefghij01234567896:valuesl6:axje.uee1:t2:aa1:y1:re";
+        let message = KRPCMessage::from_bencode(encoded).unwrap();
+        assert_eq!(
+            message.message,
+            KRPCMessageDetails::Response(KRPCResponse::GetPeers {
+                ip: None,
+                id: b"abcdefghij0123456789",
+                token: None,
+                values: vec![SocketAddrV4::new(Ipv4Addr::new(97, 120, 106, 101), 0x2e75)],
+                nodes: vec![],
+                nodes6: vec![],
+            })
+        );
+        assert_eq!(message.to_bencode(), encoded.to_vec());
+    }
+
+    #[test]
+    fn get_peers_nodes() {
+        let response = KRPCMessage {
+            transaction_id: Cow::Borrowed(b"aa"),
+            message: KRPCMessageDetails::Response(KRPCResponse::GetPeers {
+                ip: None,
+                id: b"abcdefghij0123456789",
+                token: Some(b"aoeusnth"),
+                values: vec![SocketAddrV4::new(Ipv4Addr::new(97, 120, 106, 101), 0x2e75)],
+                nodes: vec![NodeInfo {
+                    id: *b"mnopqrstuvwxyz123456",
+                    addr: SocketAddrV4::new(Ipv4Addr::new(105, 100, 104, 116), 0x6e6d),
+                }],
+                nodes6: vec![NodeInfo {
+                    id: *b"0123456789abcdefghij",
+                    addr: SocketAddrV6::new(
+                        Ipv6Addr::new(0x2001, 0xdb8, 0, 0, 0, 0, 0, 1),
+                        6881,
+                        0,
+                        0,
+                    ),
+                }],
+            }),
+            read_only: false,
+            version: None,
+            extra: vec![],
+        };
+        let encoded = response.to_bencode();
+        assert_eq!(KRPCMessage::from_bencode(&encoded), Ok(response));
+
+        let bad_nodes = KRPCMessage::from_bencode(
+            b"d1:rd2:id20:abcdefghij01234567895:nodes9:def456...5:token8:aoeusnthe1:t2:aa1:y1:re",
+        );
+        assert_eq!(bad_nodes, Err(DecodingError::RequiredFieldOfWrongType));
+    }
+
+    #[test_case(b"d1:rd2:id20:abcdefghij01234567895:nodes26:mnopqrstuvwxyz123456axje.u6:valuesl6:axje.uee1:t2:aa1:y1:re"; "Nodes and values")]
+    #[test_case(b"d1:rde1:t2:aa1:y1:re"; "Empty")]
+    #[test_case(b"d1:rd1:x1:ye1:t2:aa1:y1:re"; "Only unknown keys")]
+    fn ambiguous_responses(encoded: &[u8]) {
+        assert_eq!(
+            KRPCMessage::from_bencode(encoded),
+            Err(DecodingError::AmbiguousResponse)
+        );
+    }
+
+    #[test_case(201, KRPCError::GenericError("msg".to_string()))]
+    #[test_case(202, KRPCError::ServerError("msg".to_string()))]
+    #[test_case(203, KRPCError::ProtocolError("msg".to_string()))]
+    #[test_case(204, KRPCError::MethodUnknown("msg".to_string()))]
+    #[test_case(999, KRPCError::UnknownError(999, "msg".to_string()))]
+    fn error_codes(code: i64, expected: KRPCError) {
+        let err = KRPCError::from_code(code, "msg".to_string());
+        assert_eq!(err, expected);
+        assert_eq!(err.code(), code);
+        assert_eq!(err.message(), "msg");
+    }
 
     #[test]
     fn serialise_deserialise() {
         // Test serialise/deserialise error
         let expected = KRPCMessage {
-            transaction_id: b"be",
+            transaction_id: Cow::Borrowed(b"be"),
             message: KRPCMessageDetails::Error(KRPCError::ServerError("".to_string())),
+            read_only: false,
+            version: None,
+            extra: vec![],
         };
         assert_eq!(expected.to_bencode(), b"d1:eli202e0:e1:t2:be1:y1:ee");
 
         let deserialised1 = KRPCMessage::from_bencode(b"d1:eli202e0:e1:t2:be1:y1:ee");
         assert_eq!(deserialised1, Ok(expected));
 
-        // ignores unknown fields
+        // keeps unknown fields
         let deserialised2 =
             KRPCMessage::from_bencode(b"d3:abc1:d1:eli203e0:1:f4:listl1:a2:xzee1:t0:1:y1:ee");
         assert_eq!(
             deserialised2,
             Ok(KRPCMessage {
-                transaction_id: b"",
-                message: KRPCMessageDetails::Error(KRPCError::ProtocolError("".to_string()))
+                transaction_id: Cow::Borrowed(b""),
+                message: KRPCMessageDetails::Error(KRPCError::ProtocolError("".to_string())),
+                read_only: false,
+                version: None,
+                extra: vec![(b"abc", Value::String(b"d"))],
             }),
         );
 
@@ -381,29 +1235,80 @@ mod tests {
         assert_eq!(
             deserialised3,
             Ok(KRPCMessage {
-                transaction_id: b"ee",
-                message: KRPCMessageDetails::Error(KRPCError::MethodUnknown("".to_string()))
+                transaction_id: Cow::Borrowed(b"ee"),
+                message: KRPCMessageDetails::Error(KRPCError::MethodUnknown("".to_string())),
+                read_only: false,
+                version: None,
+                extra: vec![(b"123", Bencode::new(b"le").eat_any().unwrap().0)],
             }),
         );
 
         // Error examples from spec
         let error_1 = KRPCMessage {
-            transaction_id: b"aa",
+            transaction_id: Cow::Borrowed(b"aa"),
             message: KRPCMessageDetails::Error(KRPCError::GenericError(
                 "A Generic Error Ocurred".to_string(),
             )),
+            read_only: false,
+            version: None,
+            extra: vec![],
         };
         let error_1_encoded = b"d1:eli201e23:A Generic Error Ocurrede1:t2:aa1:y1:ee";
         let error_1_decoded = KRPCMessage::from_bencode(error_1_encoded);
         assert_eq!(error_1.to_bencode(), error_1_encoded.to_vec());
         assert_eq!(error_1_decoded, Ok(error_1));
 
+        // Codes we don't know survive a round trip
+        let unknown_error_encoded = b"d1:eli205e7:Unknowne1:t2:aa1:y1:ee";
+        let unknown_error = KRPCMessage::from_bencode(unknown_error_encoded);
+        assert_eq!(
+            unknown_error,
+            Ok(KRPCMessage {
+                transaction_id: Cow::Borrowed(b"aa"),
+                message: KRPCMessageDetails::Error(KRPCError::UnknownError(
+                    205,
+                    "Unknown".to_string()
+                )),
+                read_only: false,
+                version: None,
+                extra: vec![],
+            })
+        );
+        assert_eq!(
+            unknown_error.unwrap().to_bencode(),
+            unknown_error_encoded.to_vec()
+        );
+        // Used to be truncated to a u8, which made this a 201
+        assert!(matches!(
+            KRPCMessage::from_bencode(b"d1:eli457e0:e1:t2:aa1:y1:ee")
+                .unwrap()
+                .message,
+            KRPCMessageDetails::Error(KRPCError::UnknownError(457, _))
+        ));
+        // Out of range codes are still errors, so the caller can match
+        // the transaction id and see the failure
+        for encoded in [
+            &b"d1:eli65737e0:e1:t2:aa1:y1:ee"[..],
+            &b"d1:eli-1e0:e1:t2:aa1:y1:ee"[..],
+        ] {
+            let message = KRPCMessage::from_bencode(encoded).unwrap();
+            assert_eq!(message.transaction_id, &b"aa"[..]);
+            assert!(matches!(
+                message.message,
+                KRPCMessageDetails::Error(KRPCError::UnknownError(65737 | -1, _))
+            ));
+            assert_eq!(message.to_bencode(), encoded.to_vec());
+        }
+
         // Ping example from spec
         let ping_query = KRPCMessage {
-            transaction_id: b"aa",
+            transaction_id: Cow::Borrowed(b"aa"),
             message: KRPCMessageDetails::Query(KRPCQuery::Ping {
                 id: b"abcdefghij0123456789",
             }),
+            read_only: false,
+            version: None,
+            extra: vec![],
         };
         let ping_query_encoded = b"d1:ad2:id20:abcdefghij0123456789e1:q4:ping1:t2:aa1:y1:qe";
         let ping_query_decoded = KRPCMessage::from_bencode(ping_query_encoded);
@@ -412,11 +1317,15 @@ mod tests {
 
         // Get Peers from spec
         let get_peers_query = KRPCMessage {
-            transaction_id: b"aa",
+            transaction_id: Cow::Borrowed(b"aa"),
             message: KRPCMessageDetails::Query(KRPCQuery::GetPeers {
                 id: b"abcdefghij0123456789",
                 info_hash: b"mnopqrstuvwxyz123456",
+                want: None,
             }),
+            read_only: false,
+            version: None,
+            extra: vec![],
         };
         let get_peers_query_encoded = b"d1:ad2:id20:abcdefghij01234567899:info_hash20:mnopqrstuvwxyz123456e1:q9:get_peers1:t2:aa1:y1:qe";
         let get_peers_query_decoded = KRPCMessage::from_bencode(get_peers_query_encoded);
@@ -427,16 +1336,23 @@ mod tests {
         assert_eq!(get_peers_query_decoded, Ok(get_peers_query));
 
         let get_peers_response_peers = KRPCMessage {
-            transaction_id: b"aa",
+            transaction_id: Cow::Borrowed(b"aa"),
             message: KRPCMessageDetails::Response(KRPCResponse::GetPeers {
                 id: b"abcdefghij0123456789",
-                token: b"aoeusnth",
+                token: Some(b"aoeusnth"),
+                values: vec![
+                    SocketAddrV4::new(Ipv4Addr::new(97, 120, 106, 101), 0x2e75),
+                    SocketAddrV4::new(Ipv4Addr::new(105, 100, 104, 116), 0x6e6d),
+                ],
+                nodes: vec![],
+                nodes6: vec![],
                 ip: None,
             }),
+            read_only: false,
+            version: None,
+            extra: vec![],
         };
-        // d1:rd2:id20:abcdefghij01234567895:token8:aoeusnth6:valuesl6:axje.u6:idhtnmee1:t2:aa1:y1:re
-        let get_peers_response_peers_encoded =
-            b"d1:rd2:id20:abcdefghij01234567895:token8:aoeusnthe1:t2:aa1:y1:re";
+        let get_peers_response_peers_encoded = b"d1:rd2:id20:abcdefghij01234567895:token8:aoeusnth6:valuesl6:axje.u6:idhtnmee1:t2:aa1:y1:re";
         let get_peers_response_peers_decoded =
             KRPCMessage::from_bencode(get_peers_response_peers_encoded);
         assert_eq!(
@@ -448,17 +1364,24 @@ mod tests {
             Ok(get_peers_response_peers)
         );
 
-        // d1:rd2:id20:abcdefghij01234567895:nodes9:def456...5:token8:aoeusnthe1:t2:aa1:y1:re
         let get_peers_response_nodes = KRPCMessage {
-            transaction_id: b"aa",
+            transaction_id: Cow::Borrowed(b"aa"),
             message: KRPCMessageDetails::Response(KRPCResponse::GetPeers {
                 id: b"abcdefghij0123456789",
-                token: b"aoeusnth",
+                token: Some(b"aoeusnth"),
+                values: vec![],
+                nodes: vec![NodeInfo {
+                    id: *b"mnopqrstuvwxyz123456",
+                    addr: SocketAddrV4::new(Ipv4Addr::new(97, 120, 106, 101), 0x2e75),
+                }],
+                nodes6: vec![],
                 ip: None,
             }),
+            read_only: false,
+            version: None,
+            extra: vec![],
         };
-        let get_peers_response_nodes_encoded =
-            b"d1:rd2:id20:abcdefghij01234567895:token8:aoeusnthe1:t2:aa1:y1:re";
+        let get_peers_response_nodes_encoded = b"d1:rd2:id20:abcdefghij01234567895:nodes26:mnopqrstuvwxyz123456axje.u5:token8:aoeusnthe1:t2:aa1:y1:re";
         let get_peers_response_nodes_decoded =
             KRPCMessage::from_bencode(get_peers_response_nodes_encoded);
         assert_eq!(
@@ -472,11 +1395,15 @@ mod tests {
 
         // Find Node from spec
         let find_node_query = KRPCMessage {
-            transaction_id: b"aa",
+            transaction_id: Cow::Borrowed(b"aa"),
             message: KRPCMessageDetails::Query(KRPCQuery::FindNode {
                 id: b"abcdefghij0123456789",
                 target: b"mnopqrstuvwxyz123456",
+                want: None,
             }),
+            read_only: false,
+            version: None,
+            extra: vec![],
         };
         let find_node_query_encoded = b"d1:ad2:id20:abcdefghij01234567896:target20:mnopqrstuvwxyz123456e1:q9:find_node1:t2:aa1:y1:qe";
         let find_node_query_decoded = KRPCMessage::from_bencode(find_node_query_encoded);
@@ -487,15 +1414,27 @@ mod tests {
         assert_eq!(find_node_query_decoded, Ok(find_node_query));
 
         let find_node_response = KRPCMessage {
-            transaction_id: b"aa",
+            transaction_id: Cow::Borrowed(b"aa"),
             message: KRPCMessageDetails::Response(KRPCResponse::FindNode {
                 id: b"0123456789abcdefghij",
-                nodes: b"def456...",
+                nodes: vec![
+                    NodeInfo {
+                        id: *b"mnopqrstuvwxyz123456",
+                        addr: SocketAddrV4::new(Ipv4Addr::new(97, 120, 106, 101), 0x2e75),
+                    },
+                    NodeInfo {
+                        id: *b"abcdefghij0123456789",
+                        addr: SocketAddrV4::new(Ipv4Addr::new(105, 100, 104, 116), 0x6e6d),
+                    },
+                ],
+                nodes6: vec![],
                 ip: None,
             }),
+            read_only: false,
+            version: None,
+            extra: vec![],
         };
-        let find_node_response_encoded =
-            b"d1:rd2:id20:0123456789abcdefghij5:nodes9:def456...e1:t2:aa1:y1:re";
+        let find_node_response_encoded = b"d1:rd2:id20:0123456789abcdefghij5:nodes52:mnopqrstuvwxyz123456axje.uabcdefghij0123456789idhtnme1:t2:aa1:y1:re";
         let find_node_response_decoded = KRPCMessage::from_bencode(find_node_response_encoded);
         assert_eq!(
             find_node_response.to_bencode(),
@@ -503,4 +1442,428 @@ mod tests {
         );
         assert_eq!(find_node_response_decoded, Ok(find_node_response));
     }
+
+    #[test]
+    fn sample_infohashes_query() {
+        let sample_query = KRPCMessage {
+            transaction_id: Cow::Borrowed(b"aa"),
+            message: KRPCMessageDetails::Query(KRPCQuery::SampleInfohashes {
+                id: b"abcdefghij0123456789",
+                target: b"mnopqrstuvwxyz123456",
+            }),
+            read_only: false,
+            version: None,
+            extra: vec![],
+        };
+        // Only id and target are sent, there is no info_hash
+        let sample_query_encoded = b"d1:ad2:id20:abcdefghij01234567896:target20:mnopqrstuvwxyz123456e1:q17:sample_infohashes1:t2:aa1:y1:qe";
+        let sample_query_decoded = KRPCMessage::from_bencode(sample_query_encoded);
+        assert_eq!(sample_query.to_bencode(), sample_query_encoded.to_vec());
+        assert_eq!(sample_query_decoded, Ok(sample_query));
+    }
+
+    #[test]
+    fn response_with_ip() {
+        let ping_response = KRPCMessage {
+            transaction_id: Cow::Borrowed(b"aa"),
+            message: KRPCMessageDetails::Response(KRPCResponse::Ping {
+                id: b"mnopqrstuvwxyz123456",
+                ip: Some(Ip::V4 {
+                    addr: [1, 2, 3, 4],
+                    port: [0x1a, 0xe1],
+                }),
+            }),
+            read_only: false,
+            version: None,
+            extra: vec![],
+        };
+        let ping_response_encoded =
+            b"d2:ip6:\x01\x02\x03\x04\x1a\xe11:rd2:id20:mnopqrstuvwxyz123456e1:t2:aa1:y1:re";
+        let ping_response_decoded = KRPCMessage::from_bencode(ping_response_encoded);
+        assert_eq!(ping_response.to_bencode(), ping_response_encoded.to_vec());
+        assert_eq!(ping_response_decoded, Ok(ping_response));
+
+        let v6_response = KRPCMessage {
+            transaction_id: Cow::Borrowed(b"aa"),
+            message: KRPCMessageDetails::Response(KRPCResponse::Ping {
+                id: b"mnopqrstuvwxyz123456",
+                ip: Some(Ip::V6 {
+                    addr: [0x20, 0x01, 0x0d, 0xb8, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 1],
+                    port: [0x1a, 0xe1],
+                }),
+            }),
+            read_only: false,
+            version: None,
+            extra: vec![],
+        };
+        let v6_response_encoded = b"d2:ip18:\x20\x01\x0d\xb8\0\0\0\0\0\0\0\0\0\0\0\x01\x1a\xe11:rd2:id20:mnopqrstuvwxyz123456e1:t2:aa1:y1:re";
+        assert_eq!(v6_response.to_bencode(), v6_response_encoded.to_vec());
+        assert_eq!(
+            KRPCMessage::from_bencode(v6_response_encoded),
+            Ok(v6_response)
+        );
+
+        let bad_ip = KRPCMessage::from_bencode(
+            b"d2:ip5:\x01\x02\x03\x04\x1a1:rd2:id20:mnopqrstuvwxyz123456e1:t2:aa1:y1:re",
+        );
+        assert_eq!(bad_ip, Err(DecodingError::RequiredFieldOfWrongType));
+    }
+
+    #[test]
+    fn wrong_length_ids() {
+        let short_id = KRPCMessage::from_bencode(b"d1:rd2:id19:abcdefghij012345678e1:t2:aa1:y1:re");
+        assert_eq!(short_id, Err(DecodingError::InvalidIdLength));
+
+        let long_id =
+            KRPCMessage::from_bencode(b"d1:rd2:id21:abcdefghij0123456789Xe1:t2:aa1:y1:re");
+        assert_eq!(long_id, Err(DecodingError::InvalidIdLength));
+
+        let short_target = KRPCMessage::from_bencode(
+            b"d1:ad2:id20:abcdefghij01234567896:target4:mnope1:q9:find_node1:t2:aa1:y1:qe",
+        );
+        assert_eq!(short_target, Err(DecodingError::InvalidIdLength));
+
+        // Absent ids are still reported as missing
+        let no_id = KRPCMessage::from_bencode(b"d1:rd5:nodes0:e1:t2:aa1:y1:re");
+        assert_eq!(no_id, Err(DecodingError::MissingRequiredField));
+    }
+
+    #[test]
+    fn unknown_top_level_keys() {
+        let encoded = b"d1:ad2:id20:abcdefghij0123456789e1:q4:ping1:t2:aa1:v4:MC011:y1:q1:zi3ee";
+        let message = KRPCMessage::from_bencode(encoded).unwrap();
+        assert_eq!(message.extra, vec![(&b"z"[..], Value::Integer(3))]);
+        // Written back out in order, so they can be forwarded
+        assert_eq!(message.to_bencode(), encoded.to_vec());
+
+        // but never in place of a key we know about
+        let mut message = KRPCMessage::from_bencode(encoded).unwrap();
+        message.extra.push((b"y", Value::String(b"r")));
+        assert_eq!(message.to_bencode(), encoded.to_vec());
+    }
+
+    #[test_case(b"d1:ad2:id20:abcdefghij0123456789e1:q4:ping2:roi1e1:t2:aa1:y1:qe", true; "Read only")]
+    #[test_case(b"d1:ad2:id20:abcdefghij0123456789e1:q4:ping1:t2:aa1:y1:qe", false; "Not read only")]
+    fn read_only_round_trip(encoded: &[u8], read_only: bool) {
+        let message = KRPCMessage::from_bencode(encoded).unwrap();
+        assert_eq!(message.read_only, read_only);
+        assert!(message.extra.is_empty());
+        assert_eq!(message.to_bencode(), encoded.to_vec());
+    }
+
+    #[test]
+    fn read_only_zero() {
+        // Same as leaving it out, so it isn't written back
+        let message = KRPCMessage::from_bencode(
+            b"d1:ad2:id20:abcdefghij0123456789e1:q4:ping2:roi0e1:t2:aa1:y1:qe",
+        )
+        .unwrap();
+        assert!(!message.read_only);
+        assert_eq!(
+            message.to_bencode(),
+            b"d1:ad2:id20:abcdefghij0123456789e1:q4:ping1:t2:aa1:y1:qe".to_vec()
+        );
+    }
+
+    #[test_case(b"d1:ad2:id20:abcdefghij0123456789e1:q4:ping1:t2:aa1:v4:MC011:y1:qe", Some(b"MC01"); "With version")]
+    #[test_case(b"d1:ad2:id20:abcdefghij0123456789e1:q4:ping1:t2:aa1:y1:qe", None; "Without version")]
+    fn version_round_trip(encoded: &[u8], expected: Option<&[u8; 4]>) {
+        let message = KRPCMessage::from_bencode(encoded).unwrap();
+        assert_eq!(message.version, expected.map(|v| &v[..]));
+        assert!(message.extra.is_empty());
+        assert_eq!(message.to_bencode(), encoded.to_vec());
+    }
+
+    #[test]
+    fn hex_lines() {
+        let log = "64313a656c6932303265303a65313a74323a6265313a79313a6565\n\
+                   \n\
+                   64313a6164323a696432303a6162636465666768696a303132333435363738396531\
+                   3a71343a70696e67313a74323a6161313a79313a7165\n";
+        let lines: Vec<Vec<u8>> = read_hex_lines(log.as_bytes())
+            .collect::<io::Result<_>>()
+            .unwrap();
+        assert_eq!(lines.len(), 2);
+
+        assert_eq!(
+            KRPCMessage::from_bencode(&lines[0]),
+            Ok(KRPCMessage {
+                transaction_id: Cow::Borrowed(b"be"),
+                message: KRPCMessageDetails::Error(KRPCError::ServerError("".to_string())),
+                read_only: false,
+                version: None,
+                extra: vec![],
+            })
+        );
+        assert_eq!(
+            KRPCMessage::from_bencode(&lines[1]),
+            Ok(KRPCMessage {
+                transaction_id: Cow::Borrowed(b"aa"),
+                message: KRPCMessageDetails::Query(KRPCQuery::Ping {
+                    id: b"abcdefghij0123456789",
+                }),
+                read_only: false,
+                version: None,
+                extra: vec![],
+            })
+        );
+
+        let mut bad = read_hex_lines("6431zz\n".as_bytes());
+        assert_eq!(
+            bad.next().unwrap().unwrap_err().kind(),
+            io::ErrorKind::InvalidData
+        );
+    }
+
+    #[test]
+    fn sample_infohashes_response() {
+        let sample_response_encoded = b"d1:rd2:id20:0123456789abcdefghij8:intervali21600e5:nodes26:mnopqrstuvwxyz123456axje.u3:numi1000e7:samples40:mnopqrstuvwxyz123456abcdefghij0123456789e1:t2:aa1:y1:re";
+        let sample_response = KRPCMessage::from_bencode(sample_response_encoded).unwrap();
+        assert_eq!(
+            sample_response,
+            KRPCMessage {
+                transaction_id: Cow::Borrowed(b"aa"),
+                message: KRPCMessageDetails::Response(KRPCResponse::SampleInfohashes {
+                    ip: None,
+                    id: b"0123456789abcdefghij",
+                    interval: 21600,
+                    nodes: vec![NodeInfo {
+                        id: *b"mnopqrstuvwxyz123456",
+                        addr: SocketAddrV4::new(Ipv4Addr::new(97, 120, 106, 101), 0x2e75),
+                    }],
+                    num: 1000,
+                    samples: b"mnopqrstuvwxyz123456abcdefghij0123456789",
+                }),
+                read_only: false,
+                version: None,
+                extra: vec![],
+            }
+        );
+        assert_eq!(
+            sample_response.to_bencode(),
+            sample_response_encoded.to_vec()
+        );
+    }
+
+    #[test_case(b"d1:ad2:id20:abcdefghij012345678912:implied_porti1e9:info_hash20:mnopqrstuvwxyz1234564:porti6881e5:token8:aoeusnthe1:q13:announce_peer1:t2:aa1:y1:qe", 51413; "Implied port")]
+    #[test_case(b"d1:ad2:id20:abcdefghij012345678912:implied_porti0e9:info_hash20:mnopqrstuvwxyz1234564:porti6881e5:token8:aoeusnthe1:q13:announce_peer1:t2:aa1:y1:qe", 6881; "Explicit port")]
+    #[test_case(b"d1:ad2:id20:abcdefghij01234567899:info_hash20:mnopqrstuvwxyz1234564:porti6881e5:token8:aoeusnthe1:q13:announce_peer1:t2:aa1:y1:qe", 6881; "No implied port key")]
+    fn test_announced_peer(encoded: &[u8], expected_port: u16) {
+        let source = SocketAddrV4::new(Ipv4Addr::new(192, 0, 2, 7), 51413);
+        let message = KRPCMessage::from_bencode(encoded).unwrap();
+        let KRPCMessageDetails::Query(query) = message.message else {
+            panic!("Expected a query, got {:?}", message.message);
+        };
+        assert_eq!(
+            query.announced_peer(source),
+            Some(SocketAddrV4::new(*source.ip(), expected_port))
+        );
+    }
+
+    #[test]
+    fn announce_after_get_peers() {
+        let get_peers_response = KRPCMessage::from_bencode(
+            b"d1:rd2:id20:abcdefghij01234567895:token8:aoeusnth6:valuesl6:axje.uee1:t2:aa1:y1:re",
+        )
+        .unwrap();
+        let KRPCMessageDetails::Response(response) = &get_peers_response.message else {
+            panic!("Expected a response, got {:?}", get_peers_response.message);
+        };
+        let announce = KRPCMessage {
+            transaction_id: Cow::Borrowed(b"ab"),
+            message: KRPCMessageDetails::Query(
+                KRPCQuery::announce_peer(
+                    response,
+                    b"0123456789abcdefghij",
+                    b"mnopqrstuvwxyz123456",
+                    6881,
+                )
+                .unwrap(),
+            ),
+            read_only: false,
+            version: None,
+            extra: vec![],
+        };
+        assert_eq!(
+            announce.to_bencode(),
+            b"d1:ad2:id20:0123456789abcdefghij9:info_hash20:mnopqrstuvwxyz1234564:porti6881e5:token8:aoeusnthe1:q13:announce_peer1:t2:ab1:y1:qe".to_vec()
+        );
+
+        // The announce is answered with just an id
+        let announce_response =
+            KRPCMessage::from_bencode(b"d1:rd2:id20:mnopqrstuvwxyz123456e1:t2:ab1:y1:re").unwrap();
+        assert!(matches!(
+            announce_response.message,
+            KRPCMessageDetails::Response(KRPCResponse::Ping { .. })
+        ));
+
+        // Nothing to replay without a get_peers response
+        let ping = KRPCResponse::Ping {
+            ip: None,
+            id: b"mnopqrstuvwxyz123456",
+        };
+        assert_eq!(
+            KRPCQuery::announce_peer(
+                &ping,
+                b"0123456789abcdefghij",
+                b"mnopqrstuvwxyz123456",
+                6881
+            ),
+            None
+        );
+    }
+
+    #[test]
+    fn announce_peer_query() {
+        let announce_query = KRPCMessage {
+            transaction_id: Cow::Borrowed(b"aa"),
+            message: KRPCMessageDetails::Query(KRPCQuery::AnnouncePeer {
+                id: b"abcdefghij0123456789",
+                implied_port: false,
+                info_hash: b"mnopqrstuvwxyz123456",
+                port: 6881,
+                seed: false,
+                token: b"aoeusnth",
+            }),
+            read_only: false,
+            version: None,
+            extra: vec![],
+        };
+        let announce_query_encoded = b"d1:ad2:id20:abcdefghij01234567899:info_hash20:mnopqrstuvwxyz1234564:porti6881e5:token8:aoeusnthe1:q13:announce_peer1:t2:aa1:y1:qe";
+        let announce_query_decoded = KRPCMessage::from_bencode(announce_query_encoded);
+        assert_eq!(announce_query.to_bencode(), announce_query_encoded.to_vec());
+        assert_eq!(announce_query_decoded, Ok(announce_query));
+
+        let seed_query = KRPCMessage {
+            transaction_id: Cow::Borrowed(b"aa"),
+            message: KRPCMessageDetails::Query(KRPCQuery::AnnouncePeer {
+                id: b"abcdefghij0123456789",
+                implied_port: false,
+                info_hash: b"mnopqrstuvwxyz123456",
+                port: 6881,
+                seed: true,
+                token: b"aoeusnth",
+            }),
+            read_only: false,
+            version: None,
+            extra: vec![],
+        };
+        let seed_query_encoded = b"d1:ad2:id20:abcdefghij01234567899:info_hash20:mnopqrstuvwxyz1234564:porti6881e4:seedi1e5:token8:aoeusnthe1:q13:announce_peer1:t2:aa1:y1:qe";
+        assert_eq!(seed_query.to_bencode(), seed_query_encoded.to_vec());
+        assert_eq!(
+            KRPCMessage::from_bencode(seed_query_encoded),
+            Ok(seed_query)
+        );
+
+        let implied_port_query = KRPCMessage {
+            transaction_id: Cow::Borrowed(b"aa"),
+            message: KRPCMessageDetails::Query(KRPCQuery::AnnouncePeer {
+                id: b"abcdefghij0123456789",
+                implied_port: true,
+                info_hash: b"mnopqrstuvwxyz123456",
+                port: 6881,
+                seed: false,
+                token: b"aoeusnth",
+            }),
+            read_only: false,
+            version: None,
+            extra: vec![],
+        };
+        let implied_port_query_encoded = b"d1:ad2:id20:abcdefghij012345678912:implied_porti1e9:info_hash20:mnopqrstuvwxyz1234564:porti6881e5:token8:aoeusnthe1:q13:announce_peer1:t2:aa1:y1:qe";
+        assert_eq!(
+            implied_port_query.to_bencode(),
+            implied_port_query_encoded.to_vec()
+        );
+        assert_eq!(
+            KRPCMessage::from_bencode(implied_port_query_encoded),
+            Ok(implied_port_query)
+        );
+
+        // Ports have to fit in a u16
+        let bad_port = KRPCMessage::from_bencode(b"d1:ad2:id20:abcdefghij01234567899:info_hash20:mnopqrstuvwxyz1234564:porti65536e5:token8:aoeusnthe1:q13:announce_peer1:t2:aa1:y1:qe");
+        assert_eq!(bad_port, Err(DecodingError::RequiredFieldOfWrongType));
+    }
+
+    #[test]
+    fn builders() {
+        let query1 = KRPCMessage::query(KRPCQuery::Ping {
+            id: b"abcdefghij0123456789",
+        })
+        .unwrap();
+        assert_eq!(query1.transaction_id.len(), 2);
+        let transaction_ids: std::collections::HashSet<Vec<u8>> = (0..8)
+            .map(|_| {
+                KRPCMessage::query(KRPCQuery::Ping {
+                    id: b"abcdefghij0123456789",
+                })
+                .unwrap()
+                .transaction_id
+                .to_vec()
+            })
+            .collect();
+        assert!(transaction_ids.len() > 1);
+
+        let encoded = query1.to_bencode();
+        let decoded = KRPCMessage::from_bencode(&encoded).unwrap();
+        assert_eq!(decoded, query1);
+
+        let response = KRPCMessage::response(
+            &decoded,
+            KRPCResponse::Ping {
+                ip: None,
+                id: b"mnopqrstuvwxyz123456",
+            },
+        );
+        assert_eq!(response.transaction_id, query1.transaction_id);
+    }
+
+    #[test]
+    fn hex_round_trip() {
+        let get_peers_query = KRPCMessage {
+            transaction_id: Cow::Borrowed(b"aa"),
+            message: KRPCMessageDetails::Query(KRPCQuery::GetPeers {
+                id: b"abcdefghij0123456789",
+                info_hash: b"mnopqrstuvwxyz123456",
+                want: None,
+            }),
+            read_only: false,
+            version: None,
+            extra: vec![],
+        };
+        let hex = get_peers_query.to_hex();
+        assert!(hex.starts_with("64313a6164323a6964"));
+
+        let bencode = KRPCMessage::bencode_from_hex(&hex).unwrap();
+        assert_eq!(KRPCMessage::from_bencode(&bencode), Ok(get_peers_query));
+
+        assert_eq!(
+            KRPCMessage::bencode_from_hex("6g"),
+            Err(EncodingError::InvalidHashCharacter)
+        );
+        assert_eq!(
+            KRPCMessage::bencode_from_hex("643"),
+            Err(EncodingError::InvalidHashLength)
+        );
+    }
+
+    #[test]
+    fn corrupt_value_is_an_error() {
+        // The bad integer is in a key we'd otherwise ignore
+        let corrupt = KRPCMessage::from_bencode(
+            b"d1:ad2:id20:abcdefghij0123456789e1:q4:ping1:t2:aa1:xi03e1:y1:qe",
+        );
+        assert_eq!(corrupt, Err(DecodingError::InvalidInteger));
+    }
+
+    #[test]
+    fn top_level_must_be_a_dict() {
+        assert_eq!(
+            KRPCMessage::from_bencode(b"l0:e"),
+            Err(DecodingError::ExpectedDict)
+        );
+        assert_eq!(
+            KRPCMessage::from_bencode(b"i5e"),
+            Err(DecodingError::ExpectedDict)
+        );
+    }
 }
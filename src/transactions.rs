@@ -0,0 +1,110 @@
+use mainline_client::messages::{KRPCMessage, KRPCMessageDetails, KRPCQuery};
+use std::collections::HashMap;
+
+/// What we asked for, kept until the response with the same transaction id
+/// turns up. Responses don't say which query they answer, so this is the
+/// only way to know.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PendingQuery {
+    Ping,
+    FindNode { target: [u8; 20] },
+    GetPeers { info_hash: [u8; 20] },
+    AnnouncePeer { info_hash: [u8; 20] },
+    SampleInfohashes { target: [u8; 20] },
+}
+
+impl From<&KRPCQuery<'_>> for PendingQuery {
+    fn from(query: &KRPCQuery) -> PendingQuery {
+        match query {
+            KRPCQuery::Ping { .. } => PendingQuery::Ping,
+            KRPCQuery::FindNode { target, .. } => PendingQuery::FindNode { target: **target },
+            KRPCQuery::GetPeers { info_hash, .. } => PendingQuery::GetPeers {
+                info_hash: **info_hash,
+            },
+            KRPCQuery::AnnouncePeer { info_hash, .. } => PendingQuery::AnnouncePeer {
+                info_hash: **info_hash,
+            },
+            KRPCQuery::SampleInfohashes { target, .. } => {
+                PendingQuery::SampleInfohashes { target: **target }
+            }
+        }
+    }
+}
+
+#[derive(Default)]
+pub struct TransactionTable {
+    pending: HashMap<Vec<u8>, PendingQuery>,
+}
+
+impl TransactionTable {
+    pub fn register(&mut self, transaction_id: &[u8], query: PendingQuery) {
+        self.pending.insert(transaction_id.to_vec(), query);
+    }
+
+    /// Takes the query that `message` is answering out of the table. Errors
+    /// count as answers too. Returns `None` for queries from other nodes and
+    /// for transaction ids we never sent, which are either late or forged.
+    pub fn match_response(&mut self, message: &KRPCMessage) -> Option<PendingQuery> {
+        match message.message {
+            KRPCMessageDetails::Query(_) => None,
+            _ => self.pending.remove(message.transaction_id.as_ref()),
+        }
+    }
+
+    #[cfg(test)]
+    pub fn len(&self) -> usize {
+        self.pending.len()
+    }
+
+    #[cfg(test)]
+    pub fn is_empty(&self) -> bool {
+        self.pending.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use mainline_client::messages::bencode::FromBencode;
+
+    #[test]
+    fn out_of_order_responses() {
+        let mut table = TransactionTable::default();
+        let find_node = KRPCMessage::from_bencode(b"d1:ad2:id20:abcdefghij01234567896:target20:mnopqrstuvwxyz123456e1:q9:find_node1:t2:aa1:y1:qe").unwrap();
+        if let KRPCMessageDetails::Query(query) = &find_node.message {
+            table.register(&find_node.transaction_id, query.into());
+        }
+        table.register(b"bb", PendingQuery::Ping);
+        assert_eq!(table.len(), 2);
+
+        let ping_response =
+            KRPCMessage::from_bencode(b"d1:rd2:id20:mnopqrstuvwxyz123456e1:t2:bb1:y1:re").unwrap();
+        let find_node_error =
+            KRPCMessage::from_bencode(b"d1:eli202e12:Server Errore1:t2:aa1:y1:ee").unwrap();
+        assert_eq!(
+            table.match_response(&ping_response),
+            Some(PendingQuery::Ping)
+        );
+        assert_eq!(
+            table.match_response(&find_node_error),
+            Some(PendingQuery::FindNode {
+                target: *b"mnopqrstuvwxyz123456"
+            })
+        );
+        assert!(table.is_empty());
+
+        // Each transaction only gets one answer
+        assert_eq!(table.match_response(&ping_response), None);
+    }
+
+    #[test]
+    fn queries_dont_match() {
+        let mut table = TransactionTable::default();
+        table.register(b"aa", PendingQuery::Ping);
+        let ping_query =
+            KRPCMessage::from_bencode(b"d1:ad2:id20:abcdefghij0123456789e1:q4:ping1:t2:aa1:y1:qe")
+                .unwrap();
+        assert_eq!(table.match_response(&ping_query), None);
+        assert_eq!(table.len(), 1);
+    }
+}
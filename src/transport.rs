@@ -0,0 +1,108 @@
+use std::io;
+use std::net::{SocketAddr, UdpSocket};
+use std::time::Duration;
+
+pub trait Transport {
+    fn send_to(&self, buf: &[u8], addr: SocketAddr) -> io::Result<usize>;
+    fn recv_from(&self, buf: &mut [u8]) -> io::Result<(usize, SocketAddr)>;
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()>;
+}
+
+impl Transport for UdpSocket {
+    fn send_to(&self, buf: &[u8], addr: SocketAddr) -> io::Result<usize> {
+        UdpSocket::send_to(self, buf, addr)
+    }
+
+    fn recv_from(&self, buf: &mut [u8]) -> io::Result<(usize, SocketAddr)> {
+        UdpSocket::recv_from(self, buf)
+    }
+
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        UdpSocket::set_read_timeout(self, timeout)
+    }
+}
+
+#[cfg(test)]
+pub mod mock {
+    use super::*;
+    use mainline_client::messages::bencode::{FromBencode, ToBencode};
+    use mainline_client::messages::KRPCMessage;
+    use std::borrow::Cow;
+    use std::cell::{Cell, RefCell};
+    use std::collections::{HashMap, HashSet, VecDeque};
+
+    /// In memory transport. Anything sent to an address with a canned
+    /// response queues that response up to be received.
+    #[derive(Default)]
+    pub struct MockTransport {
+        responses: HashMap<SocketAddr, Vec<u8>>,
+        unreachable: HashSet<SocketAddr>,
+        pub sent: RefCell<Vec<(Vec<u8>, SocketAddr)>>,
+        pub read_timeout: Cell<Option<Duration>>,
+        inbox: RefCell<VecDeque<(Vec<u8>, SocketAddr)>>,
+    }
+
+    impl MockTransport {
+        pub fn respond_with(&mut self, addr: SocketAddr, response: &[u8]) {
+            self.responses.insert(addr, response.to_vec());
+        }
+
+        /// Sends to `addr` fail as if an ICMP port unreachable came back
+        pub fn unreachable(&mut self, addr: SocketAddr) {
+            self.unreachable.insert(addr);
+        }
+
+        /// Queues up a datagram from `from` that nothing asked for, like a
+        /// query from another node
+        pub fn deliver(&mut self, from: SocketAddr, message: &[u8]) {
+            self.inbox.borrow_mut().push_back((message.to_vec(), from));
+        }
+    }
+
+    // Canned responses are written with whatever transaction id. Give them
+    // the one from the query so they match up like a real answer would.
+    fn answer(query: &[u8], response: &[u8]) -> Vec<u8> {
+        match (
+            KRPCMessage::from_bencode(query),
+            KRPCMessage::from_bencode(response),
+        ) {
+            (Ok(query), Ok(mut response)) => {
+                response.transaction_id = Cow::Owned(query.transaction_id.to_vec());
+                response.to_bencode()
+            }
+            _ => response.to_vec(),
+        }
+    }
+
+    impl Transport for MockTransport {
+        fn send_to(&self, buf: &[u8], addr: SocketAddr) -> io::Result<usize> {
+            if self.unreachable.contains(&addr) {
+                return Err(io::ErrorKind::ConnectionRefused.into());
+            }
+            self.sent.borrow_mut().push((buf.to_vec(), addr));
+            if let Some(response) = self.responses.get(&addr) {
+                self.inbox
+                    .borrow_mut()
+                    .push_back((answer(buf, response), addr));
+            }
+            Ok(buf.len())
+        }
+
+        fn recv_from(&self, buf: &mut [u8]) -> io::Result<(usize, SocketAddr)> {
+            // Behave like a socket whose read timeout expired
+            let (message, addr) = self
+                .inbox
+                .borrow_mut()
+                .pop_front()
+                .ok_or(io::ErrorKind::WouldBlock)?;
+            let len = message.len().min(buf.len());
+            buf[..len].copy_from_slice(&message[..len]);
+            Ok((len, addr))
+        }
+
+        fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+            self.read_timeout.set(timeout);
+            Ok(())
+        }
+    }
+}